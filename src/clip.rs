@@ -0,0 +1,232 @@
+#![allow(dead_code)]
+
+// Homogeneous clipping, runs in clip space before the perspective divide.
+// Visible volume is -w <= x, y <= w, and -w <= z <= w or 0 <= z <= w for
+// reversed depth.
+
+use crate::shader::SVertexShaderOutPayload;
+use crate::varying::Varyings;
+
+/// Vertex after the vertex stage.
+pub type ClipVertex = SVertexShaderOutPayload;
+
+impl ClipVertex {
+    pub fn lerp(a: &ClipVertex, b: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            pos: glm::lerp(&a.pos, &b.pos, t),
+            varyings: Varyings::lerp(&a.varyings, &b.varyings, t, a.pos.w, b.pos.w),
+            point_size: a.point_size,
+        }
+    }
+}
+
+/// z range of the visible volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipDepth {
+    /// -w <= z <= w, near at -w
+    STANDARD,
+    /// 0 <= z <= w, near at w and far at 0
+    REVERSED,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipPlane {
+    LEFT,
+    RIGHT,
+    BOTTOM,
+    TOP,
+    NEAR,
+    FAR,
+}
+
+pub const FRUSTUM_PLANES: [ClipPlane; 6] = [
+    ClipPlane::NEAR,
+    ClipPlane::FAR,
+    ClipPlane::LEFT,
+    ClipPlane::RIGHT,
+    ClipPlane::BOTTOM,
+    ClipPlane::TOP,
+];
+
+impl ClipPlane {
+    // signed distance, >= 0 means inside
+    pub fn distance(&self, p: &glm::Vec4, depth: ClipDepth) -> f32 {
+        match (self, depth) {
+            (ClipPlane::NEAR, ClipDepth::REVERSED) => p.w - p.z,
+            (ClipPlane::FAR, ClipDepth::REVERSED) => p.z,
+            _ => self.distance_standard(p),
+        }
+    }
+
+    fn distance_standard(&self, p: &glm::Vec4) -> f32 {
+        match self {
+            ClipPlane::LEFT => p.w + p.x,
+            ClipPlane::RIGHT => p.w - p.x,
+            ClipPlane::BOTTOM => p.w + p.y,
+            ClipPlane::TOP => p.w - p.y,
+            ClipPlane::NEAR => p.w + p.z,
+            ClipPlane::FAR => p.w - p.z,
+        }
+    }
+}
+
+pub fn point_inside(p: &glm::Vec4, depth: ClipDepth) -> bool {
+    FRUSTUM_PLANES.iter().all(|plane| plane.distance(p, depth) >= 0.)
+}
+
+/// Sutherland–Hodgman against every frustum plane, returns a convex polygon
+/// (empty when fully outside).
+pub fn clip_polygon(polygon: &[&ClipVertex], depth: ClipDepth) -> Vec<ClipVertex> {
+    let mut out: Vec<ClipVertex> = polygon.iter().map(|&v| v.clone()).collect();
+    if polygon.iter().all(|v| point_inside(&v.pos, depth)) {
+        return out;
+    }
+
+    for plane in FRUSTUM_PLANES.iter() {
+        if out.is_empty() {
+            break;
+        }
+        let input = std::mem::replace(&mut out, Vec::with_capacity(polygon.len() + 6));
+        for i in 0..input.len() {
+            let cur = &input[i];
+            let next = &input[(i + 1) % input.len()];
+            let d_cur = plane.distance(&cur.pos, depth);
+            let d_next = plane.distance(&next.pos, depth);
+
+            if d_cur >= 0. {
+                out.push(cur.clone());
+            }
+            if (d_cur >= 0.) != (d_next >= 0.) {
+                let t = d_cur / (d_cur - d_next);
+                out.push(ClipVertex::lerp(cur, next, t));
+            }
+        }
+    }
+    if out.len() < 3 {
+        out.clear();
+    }
+    out
+}
+
+/// Clip a line segment against every frustum plane.
+pub fn clip_line(a: &ClipVertex, b: &ClipVertex, depth: ClipDepth) -> Option<(ClipVertex, ClipVertex)> {
+    let mut t0 = 0f32;
+    let mut t1 = 1f32;
+    for plane in FRUSTUM_PLANES.iter() {
+        let d0 = plane.distance(&a.pos, depth);
+        let d1 = plane.distance(&b.pos, depth);
+        if d0 < 0. && d1 < 0. {
+            return None;
+        }
+        if d0 < 0. {
+            t0 = f32::max(t0, d0 / (d0 - d1));
+        } else if d1 < 0. {
+            t1 = f32::min(t1, d0 / (d0 - d1));
+        }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some((ClipVertex::lerp(a, b, t0), ClipVertex::lerp(a, b, t1)))
+}
+
+/// Liang-Barsky clipping of a screen space segment against the box
+/// [min, max]. Returns the visible parameter range along the segment, `None`
+/// if nothing is visible or an end point is not finite.
+pub fn clip_segment(begin: &glm::Vec2, end: &glm::Vec2, min: &glm::Vec2, max: &glm::Vec2) -> Option<(f32, f32)> {
+    if !begin.iter().chain(end.iter()).all(|v| v.is_finite()) {
+        return None;
+    }
+    let d = end - begin;
+    let mut t0 = 0f32;
+    let mut t1 = 1f32;
+    for axis in 0..2 {
+        // p * t <= q for both sides of the box
+        for &(p, q) in [(-d[axis], begin[axis] - min[axis]), (d[axis], max[axis] - begin[axis])].iter() {
+            if p == 0. {
+                // parallel to this side
+                if q < 0. {
+                    return None;
+                }
+            } else if p < 0. {
+                t0 = f32::max(t0, q / p);
+            } else {
+                t1 = f32::min(t1, q / p);
+            }
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    Some((t0, t1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::varying::Interpolation;
+
+    fn vertex(x: f32, y: f32, z: f32, w: f32) -> ClipVertex {
+        let mut varyings = Varyings::new();
+        varyings.push(Interpolation::SMOOTH, &[z]);
+        ClipVertex {
+            pos: glm::vec4(x, y, z, w),
+            varyings,
+            point_size: None,
+        }
+    }
+
+    #[test]
+    fn test_clip_polygon_near_plane() {
+        // one vertex behind the near plane
+        let tri = [
+            vertex(0., 0., 0.5, 1.),
+            vertex(0.5, 0., 0.5, 1.),
+            vertex(0., 0., -3., 1.),
+        ];
+        let out = clip_polygon(&[&tri[0], &tri[1], &tri[2]], ClipDepth::STANDARD);
+        assert_eq!(out.len(), 4);
+        for v in &out {
+            assert!(point_inside(&v.pos, ClipDepth::STANDARD));
+            // attributes follow the position
+            assert!((v.varyings.get(0) - v.pos.z).abs() < 1e-5);
+        }
+
+        let behind = [
+            vertex(0., 0., -2., 1.),
+            vertex(0.5, 0., -2., 1.),
+            vertex(0., 0.5, -2., 1.),
+        ];
+        assert!(clip_polygon(&[&behind[0], &behind[1], &behind[2]], ClipDepth::STANDARD).is_empty());
+
+        // reversed depth keeps 0 <= z <= w, the near plane is at z = w
+        assert!(point_inside(&glm::vec4(0., 0., 0., 1.), ClipDepth::REVERSED));
+        assert!(!point_inside(&glm::vec4(0., 0., -0.5, 1.), ClipDepth::REVERSED));
+        let out = clip_polygon(&[&tri[0], &tri[1], &vertex(0., 0., 3., 1.)], ClipDepth::REVERSED);
+        assert_eq!(out.len(), 4);
+        assert!(out.iter().all(|v| v.pos.z <= v.pos.w));
+    }
+
+    #[test]
+    fn test_clip_line() {
+        let (a, b) = clip_line(&vertex(-2., 0., 0., 1.), &vertex(2., 0., 0., 1.), ClipDepth::STANDARD).unwrap();
+        assert_eq!(a.pos.x, -1.);
+        assert_eq!(b.pos.x, 1.);
+        assert!(clip_line(&vertex(2., 0., 0., 1.), &vertex(3., 0., 0., 1.), ClipDepth::STANDARD).is_none());
+    }
+
+    #[test]
+    fn test_clip_segment() {
+        let (min, max) = (glm::vec2(0f32, 0.), glm::vec2(10f32, 10.));
+        // crosses the box diagonally from far outside
+        let (t0, t1) = clip_segment(&glm::vec2(-90., -90.), &glm::vec2(110., 110.), &min, &max).unwrap();
+        assert!((t0 - 0.45).abs() < 1e-6 && (t1 - 0.5).abs() < 1e-6);
+        // inside
+        assert_eq!(clip_segment(&glm::vec2(1., 1.), &glm::vec2(2., 3.), &min, &max), Some((0., 1.)));
+        // parallel to a side, outside
+        assert!(clip_segment(&glm::vec2(-1., 1.), &glm::vec2(-1., 5.), &min, &max).is_none());
+        // passes by a corner
+        assert!(clip_segment(&glm::vec2(-5., 8.), &glm::vec2(5., 18.), &min, &max).is_none());
+        assert!(clip_segment(&glm::vec2(f32::NAN, 1.), &glm::vec2(2., 3.), &min, &max).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+
+// Triangle setup for edge function rasterization.
+//
+// Vertices are snapped to a fixed point grid so that the edge functions of
+// two triangles sharing an edge are exact negations of each other. Together
+// with the top-left rule this makes every sample on a shared edge belong to
+// exactly one of the triangles.
+//
+// Screen y goes up, so a "top" edge is a horizontal edge with the triangle
+// below it and a "left" edge has the triangle on its right.
+
+pub const SUBPIXEL_BITS: u32 = 8;
+pub const SUBPIXEL_ONE: i64 = 1 << SUBPIXEL_BITS;
+
+pub fn to_subpixel(v: f32) -> i64 {
+    (v * SUBPIXEL_ONE as f32).round() as i64
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EdgeFunctions {
+    // E_i(x, y) = a[i] * x + b[i] * y + c[i], positive inside.
+    // edge i is the one opposite to vertex i.
+    a: [i64; 3],
+    b: [i64; 3],
+    c: [i64; 3],
+    // 0 on top-left edges, -1 otherwise
+    bias: [i64; 3],
+    // E_0 + E_1 + E_2, twice the area in subpixel units
+    area: i64,
+    min: (i64, i64),
+    max: (i64, i64),
+}
+
+impl EdgeFunctions {
+    /// `None` for degenerate triangles.
+    pub fn new(v: &[glm::Vec3; 3]) -> Option<EdgeFunctions> {
+        let p: Vec<(i64, i64)> = v.iter().map(|v| (to_subpixel(v.x), to_subpixel(v.y))).collect();
+
+        let mut a = [0i64; 3];
+        let mut b = [0i64; 3];
+        let mut c = [0i64; 3];
+        for i in 0..3 {
+            let (p0, p1) = (p[(i + 1) % 3], p[(i + 2) % 3]);
+            a[i] = p0.1 - p1.1;
+            b[i] = p1.0 - p0.0;
+            c[i] = p0.0 * p1.1 - p0.1 * p1.0;
+        }
+
+        let mut area = a[0] * p[0].0 + b[0] * p[0].1 + c[0];
+        if area == 0 {
+            return None;
+        }
+        if area < 0 {
+            // clockwise, flip so the inside is positive
+            for i in 0..3 {
+                a[i] = -a[i];
+                b[i] = -b[i];
+                c[i] = -c[i];
+            }
+            area = -area;
+        }
+
+        let mut bias = [0i64; 3];
+        for i in 0..3 {
+            let top_left = a[i] > 0 || (a[i] == 0 && b[i] < 0);
+            bias[i] = if top_left { 0 } else { -1 };
+        }
+
+        Some(EdgeFunctions {
+            a,
+            b,
+            c,
+            bias,
+            area,
+            min: (p.iter().map(|p| p.0).min().unwrap(), p.iter().map(|p| p.1).min().unwrap()),
+            max: (p.iter().map(|p| p.0).max().unwrap(), p.iter().map(|p| p.1).max().unwrap()),
+        })
+    }
+
+    /// Pixels that may hold a covered sample, inclusive.
+    pub fn bounding_box(&self) -> (glm::I32Vec2, glm::I32Vec2) {
+        (
+            glm::vec2((self.min.0 >> SUBPIXEL_BITS) as i32, (self.min.1 >> SUBPIXEL_BITS) as i32),
+            glm::vec2((self.max.0 >> SUBPIXEL_BITS) as i32, (self.max.1 >> SUBPIXEL_BITS) as i32),
+        )
+    }
+
+    /// Edge values at a point in subpixel units.
+    pub fn evaluate(&self, x: i64, y: i64) -> [i64; 3] {
+        [
+            self.a[0] * x + self.b[0] * y + self.c[0],
+            self.a[1] * x + self.b[1] * y + self.c[1],
+            self.a[2] * x + self.b[2] * y + self.c[2],
+        ]
+    }
+
+    /// Add to the edge values to move one pixel right.
+    pub fn step_x(&self) -> [i64; 3] {
+        [self.a[0] * SUBPIXEL_ONE, self.a[1] * SUBPIXEL_ONE, self.a[2] * SUBPIXEL_ONE]
+    }
+
+    pub fn inside(&self, e: &[i64; 3]) -> bool {
+        e[0] + self.bias[0] >= 0 && e[1] + self.bias[1] >= 0 && e[2] + self.bias[2] >= 0
+    }
+
+    pub fn barycentric(&self, e: &[i64; 3]) -> glm::Vec3 {
+        let area = self.area as f64;
+        glm::vec3(
+            (e[0] as f64 / area) as f32,
+            (e[1] as f64 / area) as f32,
+            (e[2] as f64 / area) as f32,
+        )
+    }
+
+    /// Barycentric coordinates at any screen position.
+    pub fn barycentric_at(&self, x: f32, y: f32) -> glm::Vec3 {
+        let x = x as f64 * SUBPIXEL_ONE as f64;
+        let y = y as f64 * SUBPIXEL_ONE as f64;
+        let area = self.area as f64;
+        let mut out = glm::vec3(0f32, 0., 0.);
+        for i in 0..3 {
+            out[i] = ((self.a[i] as f64 * x + self.b[i] as f64 * y + self.c[i] as f64) / area) as f32;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn covered(v: &[glm::Vec3; 3], x: i32, y: i32) -> bool {
+        let edges = EdgeFunctions::new(v).unwrap();
+        let half = SUBPIXEL_ONE / 2;
+        edges.inside(&edges.evaluate(x as i64 * SUBPIXEL_ONE + half, y as i64 * SUBPIXEL_ONE + half))
+    }
+
+    #[test]
+    fn test_shared_edge_covered_once() {
+        // a quad split along its diagonal, corners on pixel centers so
+        // many samples fall exactly on the edges
+        let p = [
+            glm::vec3(0.5f32, 0.5, 0.),
+            glm::vec3(8.5, 0.5, 0.),
+            glm::vec3(8.5, 8.5, 0.),
+            glm::vec3(0.5, 8.5, 0.),
+        ];
+        let t0 = [p[0], p[1], p[2]];
+        let t1 = [p[0], p[2], p[3]];
+        // the other winding of the same triangles
+        let t2 = [p[2], p[1], p[0]];
+        let t3 = [p[3], p[2], p[0]];
+
+        for y in -1..10 {
+            for x in -1..10 {
+                let a = covered(&t0, x, y) as u32 + covered(&t1, x, y) as u32;
+                let b = covered(&t2, x, y) as u32 + covered(&t3, x, y) as u32;
+                // left and top edges are in, right and bottom edges are out
+                let inside_quad = x >= 0 && x < 8 && y >= 1 && y < 9;
+                assert_eq!(a, inside_quad as u32, "pixel ({}, {})", x, y);
+                assert_eq!(b, inside_quad as u32, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_barycentric() {
+        let v = [glm::vec3(0f32, 0., 0.), glm::vec3(4., 0., 0.), glm::vec3(0., 4., 0.)];
+        let edges = EdgeFunctions::new(&v).unwrap();
+        let at_vertex = edges.barycentric(&edges.evaluate(4 * SUBPIXEL_ONE, 0));
+        assert_eq!(at_vertex, glm::vec3(0., 1., 0.));
+        let center = edges.barycentric_at(1., 1.);
+        assert!((center - glm::vec3(0.5, 0.25, 0.25)).norm() < 1e-6);
+        assert!(EdgeFunctions::new(&[v[0], v[1], v[1]]).is_none());
+    }
+}
@@ -0,0 +1,363 @@
+#![allow(dead_code)]
+
+// Color and depth storage of the rasterizer.
+//
+// Every buffer is row major with the top row first, screen y goes up.
+// For parallel rasterization the buffers are split into tiles of whole rows,
+// so each tile owns a contiguous `&mut` slice of every buffer and can be
+// handed to its own thread without any locking.
+//
+// A coarse depth buffer (hierarchical z) keeps the depth range of every
+// HIZ_BLOCK x HIZ_BLOCK block. It is refreshed lazily, a depth write only
+// marks its block dirty.
+
+use crate::gbuffer::GBufferTexel;
+
+/// Rows per tile.
+pub const TILE_ROWS: u32 = 16;
+/// Pixels per side of a hierarchical z block, divides TILE_ROWS.
+pub const HIZ_BLOCK: u32 = 8;
+
+/// Depth range of all samples in a block.
+#[derive(Debug, Clone, Copy)]
+pub struct HizBlock {
+    min: f32,
+    max: f32,
+    dirty: bool,
+}
+
+pub struct FrameBuffer {
+    width: u32,
+    height: u32,
+    msaa: u32,
+
+    // one buffer per sample, [msaa] holds the resolved result
+    color: Vec<Vec<glm::Vec4>>,
+    depth: Vec<Vec<f32>>,
+    // one buffer per sample, nothing to resolve
+    stencil: Vec<Vec<u8>>,
+    // row major like the buffers, covers the sample depths
+    hiz: Vec<HizBlock>,
+    // one texel per pixel, `None` where nothing was drawn
+    gbuffer: Vec<Option<GBufferTexel>>,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer {
+            width: 0,
+            height: 0,
+            msaa: 0,
+            color: Vec::new(),
+            depth: Vec::new(),
+            stencil: Vec::new(),
+            hiz: Vec::new(),
+            gbuffer: Vec::new(),
+        }
+    }
+}
+
+impl FrameBuffer {
+    pub fn new(width: u32, height: u32, msaa: u32) -> FrameBuffer {
+        let size = (width * height) as usize;
+        FrameBuffer {
+            width,
+            height,
+            msaa,
+            color: vec![vec![glm::Vec4::zeros(); size]; msaa as usize + 1],
+            depth: vec![vec![0f32; size]; msaa as usize + 1],
+            stencil: vec![vec![0u8; size]; msaa as usize],
+            hiz: vec![HizBlock { min: 0., max: 0., dirty: false }; (hiz_row_len(width) * hiz_rows(height)) as usize],
+            gbuffer: vec![None; size],
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn clear_color(&mut self, color: &glm::Vec4) {
+        for buf in self.color.iter_mut() {
+            buf.iter_mut().for_each(|c| *c = *color);
+        }
+    }
+
+    pub fn clear_depth(&mut self, depth: f32) {
+        for buf in self.depth.iter_mut() {
+            buf.iter_mut().for_each(|d| *d = depth);
+        }
+        self.hiz.iter_mut().for_each(|b| *b = HizBlock { min: depth, max: depth, dirty: false });
+    }
+
+    pub fn clear_stencil(&mut self, stencil: u8) {
+        for buf in self.stencil.iter_mut() {
+            buf.iter_mut().for_each(|s| *s = stencil);
+        }
+    }
+
+    pub fn clear_gbuffer(&mut self) {
+        self.gbuffer.iter_mut().for_each(|t| *t = None);
+    }
+
+    pub fn gbuffer(&self) -> &[Option<GBufferTexel>] {
+        &self.gbuffer
+    }
+
+    pub fn resolved_color(&self) -> &[glm::Vec4] {
+        &self.color[self.msaa as usize]
+    }
+
+    pub fn resolved_color_mut(&mut self) -> &mut [glm::Vec4] {
+        &mut self.color[self.msaa as usize]
+    }
+
+    pub fn resolved_depth(&self) -> &[f32] {
+        &self.depth[self.msaa as usize]
+    }
+
+    pub fn tile_count(&self) -> usize {
+        ((self.height + TILE_ROWS - 1) / TILE_ROWS) as usize
+    }
+
+    /// Tile holding screen row `y`.
+    pub fn tile_of(&self, y: i32) -> usize {
+        ((self.height as i32 - 1 - y) / TILE_ROWS as i32) as usize
+    }
+
+    /// Only tile `k`, for writes that touch a few pixels.
+    pub fn tile_mut(&mut self, k: usize) -> Tile<'_> {
+        let (width, height, msaa) = (self.width, self.height, self.msaa);
+        let top = height - k as u32 * TILE_ROWS;
+        let chunk = (width * TILE_ROWS) as usize;
+        let rows = |len: usize| k * chunk..usize::min((k + 1) * chunk, len);
+        let hiz_chunk = (hiz_row_len(width) * TILE_ROWS / HIZ_BLOCK) as usize;
+        let hiz_len = self.hiz.len();
+        let gbuffer_len = self.gbuffer.len();
+        Tile {
+            width,
+            msaa,
+            y_min: top.saturating_sub(TILE_ROWS) as i32,
+            y_max: top as i32,
+            color: self.color.iter_mut().map(|buf| { let r = rows(buf.len()); &mut buf[r] }).collect(),
+            depth: self.depth.iter_mut().map(|buf| { let r = rows(buf.len()); &mut buf[r] }).collect(),
+            stencil: self.stencil.iter_mut().map(|buf| { let r = rows(buf.len()); &mut buf[r] }).collect(),
+            hiz: &mut self.hiz[k * hiz_chunk..usize::min((k + 1) * hiz_chunk, hiz_len)],
+            gbuffer: &mut self.gbuffer[rows(gbuffer_len)],
+        }
+    }
+
+    pub fn tiles_mut(&mut self) -> Vec<Tile<'_>> {
+        let (width, height, msaa) = (self.width, self.height, self.msaa);
+        let mut tiles: Vec<Tile> = (0..self.tile_count() as u32).map(|k| {
+            let top = height - k * TILE_ROWS;
+            Tile {
+                width,
+                msaa,
+                y_min: top.saturating_sub(TILE_ROWS) as i32,
+                y_max: top as i32,
+                color: Vec::with_capacity(msaa as usize + 1),
+                depth: Vec::with_capacity(msaa as usize + 1),
+                stencil: Vec::with_capacity(msaa as usize),
+                hiz: &mut [],
+                gbuffer: &mut [],
+            }
+        }).collect();
+
+        let chunk = (width * TILE_ROWS) as usize;
+        for buf in self.color.iter_mut() {
+            for (tile, rows) in tiles.iter_mut().zip(buf.chunks_mut(chunk)) {
+                tile.color.push(rows);
+            }
+        }
+        for buf in self.depth.iter_mut() {
+            for (tile, rows) in tiles.iter_mut().zip(buf.chunks_mut(chunk)) {
+                tile.depth.push(rows);
+            }
+        }
+        for buf in self.stencil.iter_mut() {
+            for (tile, rows) in tiles.iter_mut().zip(buf.chunks_mut(chunk)) {
+                tile.stencil.push(rows);
+            }
+        }
+        let hiz_chunk = (hiz_row_len(width) * TILE_ROWS / HIZ_BLOCK) as usize;
+        for (tile, blocks) in tiles.iter_mut().zip(self.hiz.chunks_mut(hiz_chunk)) {
+            tile.hiz = blocks;
+        }
+        for (tile, rows) in tiles.iter_mut().zip(self.gbuffer.chunks_mut(chunk)) {
+            tile.gbuffer = rows;
+        }
+        tiles
+    }
+}
+
+fn hiz_row_len(width: u32) -> u32 {
+    (width + HIZ_BLOCK - 1) / HIZ_BLOCK
+}
+
+fn hiz_rows(height: u32) -> u32 {
+    (height + HIZ_BLOCK - 1) / HIZ_BLOCK
+}
+
+/// Rows [y_min, y_max) of every buffer.
+pub struct Tile<'a> {
+    width: u32,
+    msaa: u32,
+    pub y_min: i32,
+    pub y_max: i32,
+
+    color: Vec<&'a mut [glm::Vec4]>,
+    depth: Vec<&'a mut [f32]>,
+    stencil: Vec<&'a mut [u8]>,
+    hiz: &'a mut [HizBlock],
+    gbuffer: &'a mut [Option<GBufferTexel>],
+}
+
+impl<'a> Tile<'a> {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width as i32 && y >= self.y_min && y < self.y_max
+    }
+
+    pub fn index(&self, x: i32, y: i32) -> usize {
+        debug_assert!(self.contains(x, y));
+        ((self.y_max - 1 - y) as u32 * self.width + x as u32) as usize
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.gbuffer.len()
+    }
+
+    /// Screen position of pixel `idx`, inverse of `index`.
+    pub fn coords(&self, idx: usize) -> (i32, i32) {
+        ((idx as u32 % self.width) as i32, self.y_max - 1 - (idx as u32 / self.width) as i32)
+    }
+
+    pub fn texel(&self, idx: usize) -> Option<&GBufferTexel> {
+        self.gbuffer[idx].as_ref()
+    }
+
+    pub fn set_texel(&mut self, idx: usize, texel: GBufferTexel) {
+        self.gbuffer[idx] = Some(texel);
+    }
+
+    pub fn color(&self, sample: usize, idx: usize) -> glm::Vec4 {
+        self.color[sample][idx]
+    }
+
+    pub fn set_color(&mut self, sample: usize, idx: usize, color: &glm::Vec4) {
+        self.color[sample][idx] = *color;
+    }
+
+    pub fn depth(&self, sample: usize, idx: usize) -> f32 {
+        self.depth[sample][idx]
+    }
+
+    pub fn set_depth(&mut self, sample: usize, idx: usize, depth: f32) {
+        self.depth[sample][idx] = depth;
+        if sample < self.msaa as usize {
+            let block = self.block_of_index(idx);
+            self.hiz[block].dirty = true;
+        }
+    }
+
+    /// Hierarchical z block holding pixel (x, y).
+    pub fn block_of(&self, x: i32, y: i32) -> usize {
+        self.block_of_index(self.index(x, y))
+    }
+
+    fn block_of_index(&self, idx: usize) -> usize {
+        let (row, col) = (idx as u32 / self.width, idx as u32 % self.width);
+        ((row / HIZ_BLOCK) * hiz_row_len(self.width) + col / HIZ_BLOCK) as usize
+    }
+
+    /// (min, max) depth of every sample in `block`.
+    pub fn depth_bounds(&mut self, block: usize) -> (f32, f32) {
+        if self.hiz[block].dirty {
+            let row_len = hiz_row_len(self.width) as usize;
+            let rows = self.depth[0].len() / self.width as usize;
+            let (row0, col0) = ((block / row_len) * HIZ_BLOCK as usize, (block % row_len) * HIZ_BLOCK as usize);
+            let mut min = f32::INFINITY;
+            let mut max = f32::NEG_INFINITY;
+            for buf in self.depth[..self.msaa as usize].iter() {
+                for row in row0..usize::min(row0 + HIZ_BLOCK as usize, rows) {
+                    let start = row * self.width as usize;
+                    let cols = col0..usize::min(col0 + HIZ_BLOCK as usize, self.width as usize);
+                    for &d in buf[start + cols.start..start + cols.end].iter() {
+                        min = f32::min(min, d);
+                        max = f32::max(max, d);
+                    }
+                }
+            }
+            self.hiz[block] = HizBlock { min, max, dirty: false };
+        }
+        (self.hiz[block].min, self.hiz[block].max)
+    }
+
+    pub fn stencil(&self, sample: usize, idx: usize) -> u8 {
+        self.stencil[sample][idx]
+    }
+
+    pub fn set_stencil(&mut self, sample: usize, idx: usize, stencil: u8) {
+        self.stencil[sample][idx] = stencil;
+    }
+
+    /// Write every sample and the resolved pixel.
+    pub fn set_pixel(&mut self, idx: usize, color: &glm::Vec4) {
+        for buf in self.color.iter_mut() {
+            buf[idx] = *color;
+        }
+    }
+
+    // 合并各buffer信息
+    pub fn resolve(&mut self, idx: usize) {
+        let msaa = self.msaa as usize;
+        let mut color = glm::Vec4::zeros();
+        let mut depth = 0f32;
+        for s_idx in 0..msaa {
+            color += self.color[s_idx][idx] / msaa as f32;
+            depth += self.depth[s_idx][idx] / msaa as f32;
+        }
+        self.color[msaa][idx] = color;
+        self.depth[msaa][idx] = depth;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth_bounds() {
+        let mut frame_buf = FrameBuffer::new(20, 20, 2);
+        frame_buf.clear_depth(1.);
+        let mut tiles = frame_buf.tiles_mut();
+        // top tile holds rows 4..20
+        let tile = &mut tiles[0];
+        let block = tile.block_of(17, 19);
+        assert_eq!(tile.depth_bounds(block), (1., 1.));
+
+        let idx = tile.index(17, 19);
+        tile.set_depth(1, idx, 0.25);
+        assert_eq!(tile.depth_bounds(block), (0.25, 1.));
+        // the resolved buffer is not part of the bounds
+        tile.set_depth(2, idx, 0.);
+        assert_eq!(tile.depth_bounds(block), (0.25, 1.));
+        // other blocks are untouched
+        assert_eq!(tile.depth_bounds(tile.block_of(0, 19)), (1., 1.));
+    }
+
+    #[test]
+    fn test_tile_mut() {
+        let mut frame_buf = FrameBuffer::new(20, 20, 2);
+        // the bottom tile only has rows 0..4
+        let k = frame_buf.tile_of(2);
+        let mut tile = frame_buf.tile_mut(k);
+        assert_eq!((tile.y_min, tile.y_max), (0, 4));
+        let idx = tile.index(5, 2);
+        tile.set_pixel(idx, &glm::vec4(1., 1., 1., 1.));
+        tile.set_depth(0, idx, 0.5);
+
+        let tiles = frame_buf.tiles_mut();
+        assert_eq!(tiles[k].color(2, idx), glm::vec4(1., 1., 1., 1.));
+        assert_eq!(tiles[k].depth(0, idx), 0.5);
+    }
+}
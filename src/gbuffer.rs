@@ -0,0 +1,33 @@
+#![allow(dead_code)]
+
+// Deferred shading.
+//
+// The geometry pass stores the surface attributes of the visible fragment of
+// every pixel, the lighting pass shades each covered pixel once afterwards.
+// The G-buffer holds one texel per pixel, with msaa the last fragment that
+// passed the depth test on any sample of a pixel owns it.
+
+/// Whether `draw` runs the frame shader or fills the G-buffer.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadingMode {
+    FORWARD,
+    DEFERRED,
+}
+
+impl Default for ShadingMode {
+    fn default() -> Self {
+        ShadingMode::FORWARD
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GBufferTexel {
+    // world space
+    pub position: glm::Vec3,
+    pub normal: glm::Vec3,
+    // rgba
+    pub albedo: glm::Vec4,
+    pub uv: glm::Vec2,
+    pub material_id: u32,
+}
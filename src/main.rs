@@ -9,10 +9,19 @@ mod triangle;
 mod rasterizer;
 mod utility;
 mod shader_utility;
+mod msaa;
+mod clip;
+mod render_state;
+mod frame_buffer;
+mod edge_function;
+mod varying;
+mod uniform;
+mod gbuffer;
+mod shadow;
 
 use triangle::*;
 use rasterizer::*;
-use opencv::{core::{self, CV_32FC2, CV_32FC3, CV_8UC3, Vector}, highgui, imgcodecs::{self, IMREAD_COLOR, imwrite, imwritemulti}, imgproc::{self, COLOR_BGR2RGB, COLOR_RGB2BGR}, prelude::*, videoio};
+use opencv::{core::{self, CV_32FC2, CV_32FC4, CV_8UC4, Vector}, highgui, imgcodecs::{self, IMREAD_COLOR, imwrite, imwritemulti}, imgproc::{self, COLOR_BGR2RGB, COLOR_RGB2BGR}, prelude::*, videoio};
 use std::io::BufReader;
 use std::default;
 use crate::shader_program::*;
@@ -138,13 +147,17 @@ fn main(){
     let proj_mat =
         glm::perspective_fov_lh(3.14f32/6.0, width as f32, height as f32, 0.1, 100.0);
 
+    // 左手坐标系下投影到屏幕后正面是顺时针
+    rst.set_front_face(render_state::FrontFace::CW);
+    rst.set_cull_mode(render_state::CullMode::BACK);
+
     // set fragment shader
     rst.set_frame_shader(Box::new(bump_fs));
     // set fragment shader value
-    rst.set_cfv_eye_pos(eye.clone());
+    rst.set_uniform("eye_pos", eye.clone());
     // 加载任意贴图
     let texture0 = imread("./models/spot/hmap.jpg", IMREAD_COLOR).unwrap();
-    rst.set_cfv_texture0(texture0);
+    rst.bind_texture(0, &texture0).unwrap();
 
     // 组装数据 --end
 
@@ -163,13 +176,13 @@ fn main(){
 
         let mat = unsafe {
             Mat::new_nd_with_data(
-                &[width as i32, height as i32], CV_32FC3, 
+                &[width as i32, height as i32], CV_32FC4, 
                 rst.frame_buf_ptr(),
                 None).unwrap()
         };
 
         let mut out_mat = Mat::default();
-        mat.convert_to(&mut out_mat, CV_8UC3, 255.0, 0.0).unwrap();
+        mat.convert_to(&mut out_mat, CV_8UC4, 255.0, 0.0).unwrap();
         println!("{:?}", out_mat);
 
         let mut params = Vector::new();
@@ -194,13 +207,13 @@ fn main(){
 
         let mat = unsafe {
             Mat::new_nd_with_data(
-                &[width as i32, height as i32], CV_32FC3, 
+                &[width as i32, height as i32], CV_32FC4, 
                 rst.frame_buf_ptr(),
                 None).unwrap()
         };
 
         let mut out_mat = Mat::default();
-        mat.convert_to(&mut out_mat, CV_8UC3, 255.0, 0.0).unwrap();
+        mat.convert_to(&mut out_mat, CV_8UC4, 255.0, 0.0).unwrap();
 
         highgui::imshow(win_name, &out_mat).unwrap();
 
@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+/// Sample positions inside a pixel, in [0, 1) pixel space.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplePattern {
+    /// regular n x m grid
+    GRID,
+    /// grid rotated so that no two samples share a row or a column
+    ROTATED_GRID,
+    /// D3D standard sample positions
+    STANDARD,
+    /// user defined positions, one per sample
+    CUSTOM(Vec<(f32, f32)>),
+}
+
+/// How the samples of a pixel get their color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiAliasing {
+    /// run the frame shader for every covered sample (supersampling)
+    SSAA,
+    /// test coverage and depth per sample, run the frame shader once per pixel
+    /// at the centroid of the covered samples
+    MSAA,
+}
+
+/// Why a sample count and pattern can't be used.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SampleError {
+    /// not one of SUPPORTED_SAMPLE_COUNTS
+    UNSUPPORTED_COUNT(u32),
+    /// custom pattern with another number of positions than samples
+    PATTERN_LENGTH { count: u32, positions: usize },
+    /// custom position outside [0, 1)
+    OUTSIDE_PIXEL(f32, f32),
+}
+
+pub const SUPPORTED_SAMPLE_COUNTS: [u32; 5] = [1, 2, 4, 8, 16];
+
+// D3D standard positions, in 1/16 pixel relative to the pixel center
+const D3D_STANDARD_2X: [(i32, i32); 2] = [(4, 4), (-4, -4)];
+const D3D_STANDARD_4X: [(i32, i32); 4] = [(-2, -6), (6, -2), (-6, 2), (2, 6)];
+const D3D_STANDARD_8X: [(i32, i32); 8] = [
+    (1, -3), (-1, 3), (5, 1), (-3, -5),
+    (-5, 5), (-7, -1), (3, 7), (7, -7),
+];
+const D3D_STANDARD_16X: [(i32, i32); 16] = [
+    (1, 1), (-1, -3), (-3, 2), (4, -1),
+    (-5, -2), (2, 5), (5, 3), (3, -5),
+    (-2, 6), (0, -7), (-4, -6), (-6, 4),
+    (-8, 0), (7, -4), (6, 7), (-7, -8),
+];
+
+fn grid_size(count: u32) -> Result<(u32, u32), SampleError> {
+    match count {
+        1 => Ok((1, 1)),
+        2 => Ok((2, 1)),
+        4 => Ok((2, 2)),
+        8 => Ok((4, 2)),
+        16 => Ok((4, 4)),
+        _ => Err(SampleError::UNSUPPORTED_COUNT(count)),
+    }
+}
+
+pub fn sample_positions(count: u32, pattern: &SamplePattern) -> Result<Vec<(f32, f32)>, SampleError> {
+    let (cols, rows) = grid_size(count)?;
+    if let SamplePattern::CUSTOM(list) = pattern {
+        if list.len() != count as usize {
+            return Err(SampleError::PATTERN_LENGTH { count, positions: list.len() });
+        }
+        for &(x, y) in list {
+            // also catches nan
+            if !(x >= 0. && x < 1. && y >= 0. && y < 1.) {
+                return Err(SampleError::OUTSIDE_PIXEL(x, y));
+            }
+        }
+        return Ok(list.clone());
+    }
+
+    let n = count as f32;
+    let mut out = Vec::with_capacity(count as usize);
+
+    match pattern {
+        SamplePattern::GRID => {
+            for gy in 0..rows {
+                for gx in 0..cols {
+                    out.push((
+                        (gx as f32 + 0.5) / cols as f32,
+                        (gy as f32 + 0.5) / rows as f32,
+                    ));
+                }
+            }
+        }
+        SamplePattern::ROTATED_GRID => {
+            // every sample gets its own column and row of an n x n sub grid
+            for gy in 0..rows {
+                for gx in 0..cols {
+                    out.push((
+                        ((gx * rows + gy) as f32 + 0.5) / n,
+                        ((gy * cols + (cols - 1 - gx)) as f32 + 0.5) / n,
+                    ));
+                }
+            }
+        }
+        SamplePattern::STANDARD => {
+            let table: &[(i32, i32)] = match count {
+                1 => &[(0, 0)],
+                2 => &D3D_STANDARD_2X,
+                4 => &D3D_STANDARD_4X,
+                8 => &D3D_STANDARD_8X,
+                _ => &D3D_STANDARD_16X,
+            };
+            for &(x, y) in table {
+                out.push((0.5 + x as f32 / 16., 0.5 + y as f32 / 16.));
+            }
+        }
+        SamplePattern::CUSTOM(_) => unreachable!(),
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_positions_inside_pixel() {
+        let patterns = [SamplePattern::GRID, SamplePattern::ROTATED_GRID, SamplePattern::STANDARD];
+        for pattern in patterns.iter() {
+            for &count in SUPPORTED_SAMPLE_COUNTS.iter() {
+                let list = sample_positions(count, pattern).unwrap();
+                assert_eq!(list.len(), count as usize);
+                for &(x, y) in &list {
+                    assert!(x >= 0. && x < 1. && y >= 0. && y < 1.);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotated_grid_unique_rows_and_cols() {
+        for &count in SUPPORTED_SAMPLE_COUNTS.iter() {
+            let list = sample_positions(count, &SamplePattern::ROTATED_GRID).unwrap();
+            for i in 0..list.len() {
+                for j in i + 1..list.len() {
+                    assert_ne!(list[i].0, list[j].0);
+                    assert_ne!(list[i].1, list[j].1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_samples() {
+        assert_eq!(sample_positions(3, &SamplePattern::GRID), Err(SampleError::UNSUPPORTED_COUNT(3)));
+        assert_eq!(sample_positions(32, &SamplePattern::STANDARD), Err(SampleError::UNSUPPORTED_COUNT(32)));
+        assert_eq!(
+            sample_positions(2, &SamplePattern::CUSTOM(vec![(0.5, 0.5)])),
+            Err(SampleError::PATTERN_LENGTH { count: 2, positions: 1 })
+        );
+        assert_eq!(
+            sample_positions(2, &SamplePattern::CUSTOM(vec![(0.5, 0.5), (1., 0.5)])),
+            Err(SampleError::OUTSIDE_PIXEL(1., 0.5))
+        );
+        let list = vec![(0.25, 0.75), (0.75, 0.25)];
+        assert_eq!(sample_positions(2, &SamplePattern::CUSTOM(list.clone())), Ok(list));
+    }
+}
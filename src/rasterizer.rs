@@ -1,13 +1,23 @@
 #![allow(dead_code)]
 
-use std::{borrow::BorrowMut, collections::HashMap};
-use std::cell::RefCell;
+use std::collections::HashMap;
 use std::default::Default;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use crate::{
     triangle::*, utility,
     shader::*,
-    shader_program::*
+    shader_program::*,
+    msaa::*,
+    clip::{self, ClipDepth, ClipVertex},
+    render_state::*,
+    frame_buffer::*,
+    edge_function::*,
+    varying::Varyings,
+    gbuffer::ShadingMode,
+    shadow::{LightSource, ShadowFace, ShadowMap},
+    uniform::{Texture, Uniforms, UniformValue},
 };
 use crate::utility::to_vec4;
 
@@ -17,6 +27,8 @@ pub struct Buffer(u32);
 impl Buffer {
     pub const COLOR: Self = Buffer(1);
     pub const DEPTH: Self = Buffer(2);
+    pub const STENCIL: Self = Buffer(4);
+    pub const GBUFFER: Self = Buffer(8);
 }
 
 impl std::ops::BitOr for Buffer {
@@ -33,10 +45,51 @@ impl std::ops::BitAnd for Buffer {
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+/// How `draw` reads the index buffer.
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Primitive {
+    /// one point per index
+    POINT,
+    /// independent lines, two indices each
     LINE,
+    /// connected lines, every index after the first adds a segment
+    LINE_STRIP,
+    /// independent triangles, three indices each
     TRIANGLE,
+    /// every index after the second adds a triangle with the two before it
+    TRIANGLE_STRIP,
+    /// every index after the second adds a triangle with the first and the previous one
+    TRIANGLE_FAN,
+}
+
+// split an index buffer into lines for the line topologies
+fn assemble_lines(primitive_type: Primitive, indices: &[u32]) -> Vec<[u32; 2]> {
+    match primitive_type {
+        Primitive::LINE => indices.chunks_exact(2).map(|l| [l[0], l[1]]).collect(),
+        Primitive::LINE_STRIP => indices.windows(2).map(|l| [l[0], l[1]]).collect(),
+        _ => panic!("{:?} is not a line primitive", primitive_type),
+    }
+}
+
+// split an index buffer into triangles for the triangle topologies
+fn assemble_triangles(primitive_type: Primitive, indices: &[u32]) -> Vec<[u32; 3]> {
+    match primitive_type {
+        Primitive::TRIANGLE => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+        Primitive::TRIANGLE_STRIP => {
+            // flip every other triangle to keep the winding consistent
+            indices.windows(3).enumerate().map(|(i, t)| {
+                if i % 2 == 0 { [t[0], t[1], t[2]] } else { [t[1], t[0], t[2]] }
+            }).collect()
+        }
+        Primitive::TRIANGLE_FAN => {
+            if indices.len() < 3 {
+                return Vec::new();
+            }
+            indices[1..].windows(2).map(|t| [indices[0], t[0], t[1]]).collect()
+        }
+        _ => panic!("{:?} is not a triangle primitive", primitive_type),
+    }
 }
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
@@ -52,52 +105,112 @@ pub struct SVertex {
     pub color: glm::Vec3,
 }
 
-const MSAA_COUNT: u32 = 1u32;
-const SAMPLE_LIST: [(f32, f32); MSAA_COUNT as usize] = [
-    // 1x msaa
-    (0.5f32, 0.5f32),
-    // 4x msaa
-    // (0.25, 0.25),
-    // (0.75, 0.25),
-    // (0.25, 0.75),
-    // (0.75, 0.75),
-    // 4x msaa
-    // (0.4, 0.1),
-    // (0.9, 0.4),
-    // (0.1, 0.6),
-    // (0.6, 0.9),
-];
-
 pub struct Rasterizer {
     model: glm::Mat4,
     view: glm::Mat4,
     projection: glm::Mat4,
 
     pos_buf: HashMap<PosBufId, Vec<SVertex>>,
-    ind_buf: HashMap<IndBufId, Vec<glm::U32Vec3>>,
+    ind_buf: HashMap<IndBufId, Vec<u32>>,
 
-    frame_bufs: Vec<RefCell<Vec<glm::Vec3>>>,
-    depth_bufs: Vec<RefCell<Vec<f32>>>,
+    frame_buf: Mutex<FrameBuffer>,
 
     width: u32,
     height: u32,
 
     next_id: u32,
 
+    vertex_shader: VertexShaderProgram,
     frame_shader: FrameShaderProgram,
+    // deferred shading
+    shading_mode: ShadingMode,
+    gbuffer_shader: GBufferShaderProgram,
+    lighting_shader: LightingShaderProgram,
+    // sample count
     msaa: u32,
-    // vertex_shader: dyn Fn(SVertexShaderPayload) -> glm::Vec3,
+    sample_list: Vec<(f32, f32)>,
+    // sample_list in subpixel units
+    sample_offsets: Vec<(i64, i64)>,
+    aa_mode: AntiAliasing,
+
+    polygon_mode: PolygonMode,
+    wireframe: WireframeState,
+    line_state: LineState,
+    point_state: PointState,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    culled_count: AtomicU32,
+    depth_state: DepthState,
+    blend_state: BlendState,
+    stencil_state: StencilState,
+    viewport: Viewport,
+    scissor: Option<Rect>,
+    clear_color: glm::Vec4,
+    thread_count: usize,
+    hierarchical_z: bool,
+    // depth and stencil only, no shading and no color or G-buffer writes
+    depth_only: bool,
+
+    // shader constants and textures
+    uniforms: Uniforms,
+
+    // target and state of the depth only pass of `render_shadow_map`, kept
+    // so the target is reused
+    shadow_pass: Option<Box<Rasterizer>>,
+}
 
-    // constant fragment shader value
-    cfv_eye_pos: glm::Vec3,
-    cfv_texture0: opencv::prelude::Mat,
+// screen space primitive waiting for rasterization
+enum RasterJob {
+    Triangle {
+        t: Triangle,
+        edges: EdgeFunctions,
+        front_facing: bool,
+    },
+    // a dot when begin == end
+    Line {
+        begin: glm::Vec3,
+        end: glm::Vec3,
+        width: f32,
+        depth_bias: f32,
+        depth_write: bool,
+        shading: LineShading,
+    },
+    Point {
+        center: glm::Vec3,
+        size: f32,
+        varyings: Varyings,
+        instance_id: u32,
+    },
+}
+
+// where line fragments get their color
+enum LineShading {
+    // frame shader on the varyings of the end points, `w` is their clip space w
+    Shaded { varyings: [Varyings; 2], w: [f32; 2], instance_id: u32 },
+    // rgba, polygon mode edges
+    Constant(glm::Vec4),
+}
+
+impl RasterJob {
+    // screen rows touched, inclusive
+    fn y_range(&self) -> (f32, f32) {
+        match self {
+            RasterJob::Triangle { t, .. } => (
+                t.v.iter().fold(f32::INFINITY, |a, v| a.min(v.y)),
+                t.v.iter().fold(f32::NEG_INFINITY, |a, v| a.max(v.y)),
+            ),
+            // one more pixel for the antialiased fringe
+            RasterJob::Line { begin, end, width, .. } => (
+                begin.y.min(end.y) - 0.5 * width - 1.,
+                begin.y.max(end.y) + 0.5 * width + 1.,
+            ),
+            RasterJob::Point { center, size, .. } => (center.y - 0.5 * size, center.y + 0.5 * size),
+        }
+    }
 }
 
 impl Default for Rasterizer{
     fn default() -> Self {
-        let mut frame_bufs = Vec::<RefCell<Vec<glm::Vec3>>>::new();
-        let mut depth_bufs = Vec::<RefCell<Vec<f32>>>::new();
-
         Rasterizer{
             model: glm::one(),
             view: glm::one(),
@@ -106,102 +219,218 @@ impl Default for Rasterizer{
             pos_buf: HashMap::new(),
             ind_buf: HashMap::new(),
 
-            frame_bufs,
-            depth_bufs,
+            frame_buf: Mutex::new(FrameBuffer::default()),
 
             width: 0u32,
             height: 0u32,
             next_id: 0u32,
 
+            vertex_shader: Box::new(default_vs),
             frame_shader: Box::new(empty_fs),
+            shading_mode: ShadingMode::FORWARD,
+            gbuffer_shader: Box::new(gbuffer_fs),
+            lighting_shader: Box::new(phone_ls),
             msaa: 0u32,
-
-            cfv_eye_pos: glm::vec3(0., 0., 0.),
-            cfv_texture0: opencv::prelude::Mat::default(),
+            sample_list: Vec::new(),
+            sample_offsets: Vec::new(),
+            aa_mode: AntiAliasing::SSAA,
+
+            polygon_mode: PolygonMode::FILL,
+            wireframe: WireframeState::default(),
+            line_state: LineState::default(),
+            point_state: PointState::default(),
+            cull_mode: CullMode::NONE,
+            front_face: FrontFace::CCW,
+            culled_count: AtomicU32::new(0),
+            depth_state: DepthState::default(),
+            blend_state: BlendState::default(),
+            stencil_state: StencilState::default(),
+            viewport: Viewport::new(0., 0., 0., 0., 0., 1.),
+            scissor: None,
+            clear_color: glm::vec4(0., 0., 0., 1.),
+            thread_count: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            hierarchical_z: true,
+            depth_only: false,
+
+            uniforms: Uniforms::new(),
+
+            shadow_pass: None,
         }
     }
 }
 
-fn inside_triangle(x: f32, y:f32, _v: &[glm::Vec3; 3]) -> bool {
-    let off = [
-        (0f32, 0f32),
-        (0f32, 1f32),
-        (1f32, 0f32),
-        (1f32, 1f32),
-    ];
-    for (off_x, off_y) in off.iter() {
-        let p = glm::vec3(x + off_x, y + off_y, 1.);
-        let mut result = 3;
-        for i in 0..3 {
-            let v0 = _v[i];
-            let v1 = _v[(i + 1) % 3];
-            let va = p - v0;
-            let vb = v1 - v0;
-            let vc = glm::cross(&va, &vb);
-            if vc.z > 0. {
-                result = result & 0x2
-            }
-            else {
-                result = result & 0x1
-            }
+// clip space w of the triangle vertices
+fn perp_w(t: &Triangle) -> [f32; 3] {
+    [t.perp_pos[0].w, t.perp_pos[1].w, t.perp_pos[2].w]
+}
+
+// derivatives of the quads of one triangle, one entry per quad column.
+// rows are visited in order, so a quad is computed once.
+struct QuadCache {
+    x0: i32,
+    quads: Vec<Option<((i32, i32), Varyings, Varyings)>>,
+}
+
+impl QuadCache {
+    fn new(x_min: i32, x_max: i32) -> QuadCache {
+        QuadCache {
+            x0: x_min >> 1,
+            quads: vec![None; ((x_max >> 1) - (x_min >> 1) + 1) as usize],
         }
-        if result != 0 {
-            return true;
+    }
+
+    fn get<F>(&mut self, x: i32, y: i32, derivatives: F) -> (&Varyings, &Varyings)
+    where
+        F: FnOnce() -> (Varyings, Varyings),
+    {
+        let quad = (x >> 1, y >> 1);
+        let entry = &mut self.quads[(quad.0 - self.x0) as usize];
+        if entry.as_ref().map_or(true, |e| e.0 != quad) {
+            let (ddx, ddy) = derivatives();
+            *entry = Some((quad, ddx, ddy));
         }
+        let (_, ddx, ddy) = entry.as_ref().unwrap();
+        (ddx, ddy)
     }
+}
+
+// newell normal of a screen space polygon, z is twice the signed area and
+// > 0 for counter clockwise. unlike one corner's cross product it stays right
+// when the clipper emits duplicate or collinear vertices.
+fn polygon_normal(v: &[glm::Vec3]) -> glm::Vec3 {
+    (0..v.len()).fold(glm::Vec3::zeros(), |n, i| n + v[i].cross(&v[(i + 1) % v.len()]))
+}
+
+// rgba <-> bgra, the frame buffer is handed to opencv as is
+fn to_bgra(color: &glm::Vec4) -> glm::Vec4 {
+    glm::vec4(color.z, color.y, color.x, color.w)
+}
 
-    return false;
+// single pixel write straight into the frame buffer, only the tile of the
+// pixel is set up
+fn write_pixel(frame_buf: &mut FrameBuffer, bounds: &Rect, point: &glm::Vec3, color: &glm::Vec4) {
+    let y = point.y as i32;
+    if !(point.y >= 0.0) || y >= frame_buf.height() as i32 {
+        return;
+    }
+    let k = frame_buf.tile_of(y);
+    set_tile_pixel(&mut frame_buf.tile_mut(k), bounds, point, color);
 }
 
-fn interpolated_value(value: &[glm::Vec3;3], z_interpolated: f32, barycentric: &glm::Vec3, pos: &[glm::Vec4;3]) -> glm::Vec3{
-    let mut out_v = glm::vec3(0f32, 0f32, 0f32);
-    for i in 0..3usize {
-        out_v[i] =
-            barycentric[0]*value[0][i]/pos[0].w +
-            barycentric[1]*value[1][i]/pos[1].w +
-            barycentric[2]*value[2][i]/pos[2].w;
-        out_v[i] *= z_interpolated;
+// pixel writes outside of the tile or `bounds` are dropped
+fn set_tile_pixel(tile: &mut Tile, bounds: &Rect, point: &glm::Vec3, color: &glm::Vec4) {
+    // also rejects NaN
+    if !(point.x >= 0.0 && point.y >= 0.0) {
+        return;
+    }
+    let (x, y) = (point.x as i32, point.y as i32);
+    if !tile.contains(x, y) || !bounds.contains(x, y) {
+        return;
     }
-    return out_v;
+    let idx = tile.index(x, y);
+    tile.set_pixel(idx, color);
 }
 
-fn compute_barycentric_2d(x:f32, y:f32, v:&[glm::Vec3; 3]) -> (f32, f32, f32) {
-    // let x = x as f64;
-    // let y = y as f64;
-    // let v = [
-    //     glm::vec3(_v[0].x as f64, _v[0].y as f64, _v[0].z as f64),
-    //     glm::vec3(_v[1].x as f64, _v[1].y as f64, _v[1].z as f64),
-    //     glm::vec3(_v[2].x as f64, _v[2].y as f64, _v[2].z as f64),
-    // ];
-    let c1 = (x*(v[1].y - v[2].y) + (v[2].x - v[1].x)*y + v[1].x*v[2].y - v[2].x*v[1].y) / (v[0].x*(v[1].y - v[2].y) + (v[2].x - v[1].x)*v[0].y + v[1].x*v[2].y - v[2].x*v[1].y);
-    let c2 = (x*(v[2].y - v[0].y) + (v[0].x - v[2].x)*y + v[2].x*v[0].y - v[0].x*v[2].y) / (v[1].x*(v[2].y - v[0].y) + (v[0].x - v[2].x)*v[1].y + v[2].x*v[0].y - v[0].x*v[2].y);
-    let c3 = (x*(v[0].y - v[1].y) + (v[1].x - v[0].x)*y + v[0].x*v[1].y - v[1].x*v[0].y) / (v[2].x*(v[0].y - v[1].y) + (v[1].x - v[0].x)*v[2].y + v[0].x*v[1].y - v[1].x*v[0].y);
-    return (c1 as f32, c2 as f32, c3 as f32);
+// `first..first + count` cut to `len`
+fn clamp_range(first: usize, count: usize, len: usize) -> std::ops::Range<usize> {
+    let end = usize::min(first.saturating_add(count), len);
+    usize::min(first, end)..end
+}
+
+// pixels covered by a line, calls `f(x, y, t, coverage)` with `t` the screen
+// space parameter of the pixel along the line. aliased lines are measured
+// along the minor axis like GL wide lines and drawn half open so strips do not
+// draw their shared points twice. antialiased lines get the coverage of a
+// capsule around the segment. begin == end makes a dot.
+fn for_each_line_pixel(begin: &glm::Vec3, end: &glm::Vec3, width: f32, antialiased: bool, bounds: &Rect, rows: (i32, i32), mut f: impl FnMut(i32, i32, f32, f32)) {
+    let half = 0.5 * f32::max(width, 1.);
+    // farthest a touched pixel center can be from the line
+    let pad = half + 1.;
+    let a = begin.xy();
+    let d = (end - begin).xy();
+    let len2 = d.norm_squared();
+
+    // only walk the part of the line inside the bounds and the rows
+    let min = glm::vec2(bounds.x as f32, i32::max(bounds.y, rows.0) as f32) - glm::vec2(pad, pad);
+    let max = glm::vec2((bounds.x + bounds.width as i32) as f32, i32::min(bounds.y + bounds.height as i32, rows.1) as f32) + glm::vec2(pad, pad);
+    let (t0, t1) = match clip::clip_segment(&a, &end.xy(), &min, &max) {
+        Some(range) => range,
+        None => return,
+    };
+    let (p0, p1) = (a + d * t0, a + d * t1);
+    let x0 = i32::max((p0.x.min(p1.x) - pad).floor() as i32, bounds.x);
+    let x1 = i32::min((p0.x.max(p1.x) + pad).ceil() as i32, bounds.x + bounds.width as i32 - 1);
+    let y0 = i32::max((p0.y.min(p1.y) - pad).floor() as i32, i32::max(bounds.y, rows.0));
+    let y1 = i32::min((p0.y.max(p1.y) + pad).ceil() as i32, i32::min(bounds.y + bounds.height as i32, rows.1) - 1);
+
+    let (major, minor) = if d.x.abs() >= d.y.abs() { (0, 1) } else { (1, 0) };
+    let inside = |offset: f32| -half < offset && offset <= half;
+    // horizontal distance from the line to the edge of the band it touches
+    let extent = if d.y != 0. { pad * len2.sqrt() / d.y.abs() } else { 0. };
+    for y in y0..=y1 {
+        // pixels of the row near the line
+        let (xs, xe) = if d.y != 0. {
+            let xc = a.x + (y as f32 + 0.5 - a.y) * d.x / d.y;
+            (i32::max(x0, (xc - extent).floor() as i32), i32::min(x1, (xc + extent).ceil() as i32))
+        } else {
+            (x0, x1)
+        };
+        for x in xs..=xe {
+            let c = glm::vec2(x as f32 + 0.5, y as f32 + 0.5);
+            let (t, coverage) = if antialiased {
+                let t = if len2 > 0. { f32::min(f32::max((c - a).dot(&d) / len2, 0.), 1.) } else { 0. };
+                let distance = (c - (a + d * t)).norm();
+                (t, f32::min(f32::max(half + 0.5 - distance, 0.), 1.))
+            } else if len2 == 0. {
+                // square dot
+                (0., if inside(c.x - a.x) && inside(c.y - a.y) { 1. } else { 0. })
+            } else {
+                let t = (c[major] - a[major]) / d[major];
+                let on_line = (0. ..1.).contains(&t) && inside(c[minor] - (a[minor] + d[minor] * t));
+                (t, if on_line { 1. } else { 0. })
+            };
+            if coverage > 0. {
+                f(x, y, t, coverage);
+            }
+        }
+    }
 }
 
 impl Rasterizer {
     pub fn new(width: u32, height: u32) -> Rasterizer {
-        let msaa = MSAA_COUNT;
-
-        let mut frame_bufs = Vec::new();
-        let mut depth_bufs = Vec::new();
-        for _ in 0..msaa + 1 {
-            let mut frame_buf = RefCell::new(Vec::new());
-            let mut depth_buf = RefCell::new(Vec::new());
-            frame_buf.borrow_mut().resize((width * height) as usize, glm::Vec3::zeros());
-            depth_buf.borrow_mut().resize((width * height) as usize, 0f32);
+        // one sample on a grid is always valid
+        Rasterizer::new_with_msaa(width, height, 1, SamplePattern::GRID).unwrap()
+    }
 
-            frame_bufs.push(frame_buf);
-            depth_bufs.push(depth_buf);
-        }
-        Rasterizer {
+    pub fn new_with_msaa(width: u32, height: u32, msaa: u32, pattern: SamplePattern) -> Result<Rasterizer, SampleError> {
+        let mut rst = Rasterizer {
             width,
             height,
-            frame_bufs,
-            depth_bufs,
-            msaa,
+            viewport: Viewport::new(0., 0., width as f32, height as f32, 0., 1.),
             ..Default::default()
-        }
+        };
+        rst.set_msaa(msaa, pattern)?;
+        Ok(rst)
+    }
+
+    /// Change the sample count (1/2/4/8/16) and pattern, reallocates all buffers.
+    /// Other counts and bad custom patterns are an error and change nothing.
+    pub fn set_msaa(&mut self, msaa: u32, pattern: SamplePattern) -> Result<(), SampleError> {
+        let sample_list = sample_positions(msaa, &pattern)?;
+
+        self.msaa = msaa;
+        self.sample_offsets = sample_list.iter().map(|s| (to_subpixel(s.0), to_subpixel(s.1))).collect();
+        self.sample_list = sample_list;
+        self.frame_buf = Mutex::new(FrameBuffer::new(self.width, self.height, msaa));
+        Ok(())
+    }
+
+    pub fn msaa(&self) -> u32 {
+        self.msaa
+    }
+
+    pub fn set_aa_mode(&mut self, mode: AntiAliasing) {
+        self.aa_mode = mode;
     }
 
     pub fn load_position(&mut self, positions: Vec<SVertex>) -> PosBufId {
@@ -212,6 +441,15 @@ impl Rasterizer {
     }
 
     pub fn load_indices(&mut self, indices: Vec<glm::U32Vec3>) -> IndBufId {
+        let mut list = Vec::with_capacity(indices.len() * 3);
+        for ind in &indices {
+            list.extend_from_slice(&[ind.x, ind.y, ind.z]);
+        }
+        self.load_index_list(list)
+    }
+
+    /// Plain index list, read according to the `Primitive` passed to `draw`.
+    pub fn load_index_list(&mut self, indices: Vec<u32>) -> IndBufId {
         let id = self.get_next_id();
         self.ind_buf.insert(IndBufId(id), indices);
 
@@ -230,220 +468,935 @@ impl Rasterizer {
         self.projection = mat.clone();
     }
 
+    /// Maps NDC to the window rect at (x, y) with size (w, h), y up,
+    /// and NDC depth to [min_depth, max_depth].
+    pub fn set_viewport(&mut self, x: f32, y: f32, w: f32, h: f32, min_depth: f32, max_depth: f32) {
+        self.viewport = Viewport::new(x, y, w, h, min_depth, max_depth);
+    }
+
+    /// Drop every pixel write outside of `rect`, `None` disables the test.
+    pub fn set_scissor(&mut self, rect: Option<Rect>) {
+        self.scissor = rect;
+    }
+
     pub fn set_pixel(&self, point: &glm::Vec3, color: &glm::Vec3) {
-        if point.x < 0.0 || point.x > self.width as f32 ||
-            point.y < 0.0 || point.y > self.height as f32 
-        {
-            return;
-        }
-        let ind = self.get_index(point.x as i32, point.y as i32);
-        self.frame_bufs[(self.msaa) as usize].borrow_mut()[ind] = color.clone();
+        let mut frame_buf = self.frame_buf.lock().unwrap();
+        write_pixel(&mut frame_buf, &self.write_bounds(), point, &utility::to_vec4(color, None));
+    }
+
+    /// Immediate 1 pixel line without depth, in screen coordinates. Clipped
+    /// to the frame buffer and the scissor first, the viewport is not used.
+    pub fn draw_line(&self, begin: &glm::Vec3, end: &glm::Vec3, color: &glm::Vec3) {
+        let bounds = self.write_bounds();
+        let min = glm::vec2(bounds.x as f32, bounds.y as f32);
+        let max = min + glm::vec2(bounds.width as f32, bounds.height as f32);
+        let (t0, t1) = match clip::clip_segment(&begin.xy(), &end.xy(), &min, &max) {
+            Some(range) => range,
+            None => return,
+        };
+
+        let mut frame_buf = self.frame_buf.lock().unwrap();
+        let color = utility::to_vec4(color, None);
+        utility::draw_line(
+            &glm::lerp(begin, end, t0),
+            &glm::lerp(begin, end, t1),
+            Box::new(|point: &glm::Vec3, _| write_pixel(&mut frame_buf, &bounds, point, &color)),
+        );
+    }
+
+    /// Worker threads used by `draw`, 1 rasterizes on the calling thread.
+    pub fn set_thread_count(&mut self, count: usize) {
+        self.thread_count = usize::max(count, 1);
+    }
+
+    pub fn set_polygon_mode(&mut self, mode: PolygonMode) {
+        self.polygon_mode = mode;
+    }
+
+    pub fn set_wireframe(&mut self, state: WireframeState) {
+        self.wireframe = state;
+    }
+
+    pub fn set_line_state(&mut self, state: LineState) {
+        self.line_state = state;
+    }
+
+    pub fn set_point_state(&mut self, state: PointState) {
+        self.point_state = state;
+    }
+
+    pub fn set_cull_mode(&mut self, mode: CullMode) {
+        self.cull_mode = mode;
+    }
+
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face;
+    }
+
+    /// Reject blocks of pixels against the coarse depth buffer before the
+    /// per sample tests, on by default. The image is the same either way.
+    pub fn set_hierarchical_z(&mut self, enable: bool) {
+        self.hierarchical_z = enable;
+    }
+
+    /// Triangles dropped by face culling since the last `reset_culled_count`.
+    pub fn culled_count(&self) -> u32 {
+        self.culled_count.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_culled_count(&self) {
+        self.culled_count.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_depth_state(&mut self, state: DepthState) {
+        self.depth_state = state;
+    }
+
+    /// Far plane at depth 0, flips the depth compare and clear value of the
+    /// current depth state with it.
+    pub fn set_reversed_z(&mut self, enable: bool) {
+        self.depth_state = self.depth_state.with_reversed_z(enable);
+    }
+
+    pub fn set_blend_state(&mut self, state: BlendState) {
+        self.blend_state = state;
+    }
+
+    pub fn set_stencil_state(&mut self, state: StencilState) {
+        self.stencil_state = state;
+    }
+
+    /// rgba color used by `clear(Buffer::COLOR)`.
+    pub fn set_clear_color(&mut self, color: glm::Vec4) {
+        self.clear_color = to_bgra(&color);
+    }
+
+    pub fn set_vertex_shader(&mut self, vertex_shader: VertexShaderProgram){
+        self.vertex_shader = vertex_shader;
     }
 
     pub fn set_frame_shader(&mut self, frame_shader: FrameShaderProgram){
         self.frame_shader = frame_shader;
     }
 
+    /// FORWARD runs the frame shader in `draw`, DEFERRED makes `draw` fill
+    /// the G-buffer and leaves the shading to `lighting_pass`.
+    pub fn set_shading_mode(&mut self, mode: ShadingMode) {
+        self.shading_mode = mode;
+    }
+
+    pub fn set_gbuffer_shader(&mut self, gbuffer_shader: GBufferShaderProgram) {
+        self.gbuffer_shader = gbuffer_shader;
+    }
+
+    pub fn set_lighting_shader(&mut self, lighting_shader: LightingShaderProgram) {
+        self.lighting_shader = lighting_shader;
+    }
+
     // constant fragment shader value set
-    pub fn set_cfv_eye_pos(&mut self, eye_pos: glm::Vec3) {
-        self.cfv_eye_pos = eye_pos;
+    pub fn set_uniform<T: UniformValue>(&mut self, name: &str, value: T) {
+        self.uniforms.set(name, value);
+    }
+
+    /// Copy the 8 bit bgr `texture` into `slot`, an empty Mat unbinds it.
+    pub fn bind_texture(&mut self, slot: usize, texture: &opencv::prelude::Mat) -> opencv::Result<()> {
+        use opencv::core::MatTrait;
+        if texture.empty()? {
+            self.uniforms.unbind_texture(slot);
+        } else {
+            self.uniforms.bind_texture(slot, Texture::from_mat(texture)?);
+        }
+        Ok(())
+    }
+
+    pub fn unbind_texture(&mut self, slot: usize) {
+        self.uniforms.unbind_texture(slot);
+    }
+
+    pub fn bind_shadow_map(&mut self, slot: usize, shadow_map: ShadowMap) {
+        self.uniforms.bind_shadow_map(slot, shadow_map);
+    }
+
+    pub fn uniforms(&self) -> &Uniforms {
+        &self.uniforms
     }
 
-    pub fn set_cfv_texture0(&mut self, texture: opencv::prelude::Mat) {
-        self.cfv_texture0 = texture;
+    pub fn uniforms_mut(&mut self) -> &mut Uniforms {
+        &mut self.uniforms
     }
 
     pub fn clear(&self, buff: Buffer) {
-        for i in 0..(self.msaa + 1) as usize {
-            if (buff & Buffer::COLOR).0 != 0 {
-                self.frame_bufs[i].borrow_mut()
-                    .iter_mut()
-                    .map(|color| *color = glm::Vec3::zeros())
-                    .count();
-            }
-            if (buff & Buffer::DEPTH).0 != 0 {
-                self.depth_bufs[i].borrow_mut()
-                    .iter_mut()
-                    .map(|depth| *depth = f32::INFINITY)
-                    .count();
-            }
+        let mut frame_buf = self.frame_buf.lock().unwrap();
+        if (buff & Buffer::COLOR).0 != 0 {
+            frame_buf.clear_color(&self.clear_color);
+        }
+        if (buff & Buffer::DEPTH).0 != 0 {
+            frame_buf.clear_depth(self.depth_state.clear_value);
+        }
+        if (buff & Buffer::STENCIL).0 != 0 {
+            frame_buf.clear_stencil(self.stencil_state.clear_value);
+        }
+        if (buff & Buffer::GBUFFER).0 != 0 {
+            frame_buf.clear_gbuffer();
         }
     }
 
-    pub fn frame_buf_sclice(&self) -> &[u8] {
-        let ptr = self.frame_bufs.as_ptr() as *const u8;
-        println!("frame buf size: {}", self.frame_bufs[0].borrow().len() * std::mem::size_of::<glm::U8Vec4>());
+    pub fn frame_buf_sclice(&mut self) -> &[u8] {
+        let resolved = self.frame_buf.get_mut().unwrap().resolved_color();
+        let ptr = resolved.as_ptr() as *const u8;
         unsafe {
-            std::slice::from_raw_parts(ptr, self.frame_bufs[0].borrow().len() * std::mem::size_of::<glm::U8Vec4>())
+            std::slice::from_raw_parts(ptr, resolved.len() * std::mem::size_of::<glm::Vec4>())
         }
     }
 
+    // resolved color buffer
     pub unsafe fn frame_buf_ptr(&mut self) -> *mut std::ffi::c_void {
-        self.frame_bufs[0].borrow_mut().as_mut_ptr() as *mut std::ffi::c_void
+        self.frame_buf.get_mut().unwrap().resolved_color_mut().as_mut_ptr() as *mut std::ffi::c_void
     }
 
     pub fn draw(&self, pos_id: PosBufId, ind_id: IndBufId, primitive_type: Primitive) {
-        if primitive_type != Primitive::TRIANGLE {
-            panic!("Drawing primitives other than triangle is not implemented yet!");
-        }
+        self.draw_instanced(pos_id, ind_id, primitive_type, &[SInstance::new(self.model)]);
+    }
+
+    /// Draw the mesh once per instance, every instance with its own model
+    /// matrix. All of them are rasterized as one batch.
+    pub fn draw_instanced(&self, pos_id: PosBufId, ind_id: IndBufId, primitive_type: Primitive, instances: &[SInstance]) {
+        let pos_buf = self.pos_buf.get(&pos_id).unwrap();
+        let ind_buf = self.ind_buf.get(&ind_id).unwrap();
+        self.draw_elements(pos_buf, ind_buf, 0, primitive_type, instances);
+    }
 
+    /// Draw indices `first..first + count` of the index buffer, the part
+    /// past its end is ignored. `base_vertex` is added to every index before
+    /// the vertex is fetched, so sub meshes can keep indices that start at 0.
+    /// Primitives with a vertex outside the position buffer are skipped.
+    pub fn draw_range(&self, pos_id: PosBufId, ind_id: IndBufId, primitive_type: Primitive, first: usize, count: usize, base_vertex: i32) {
         let pos_buf = self.pos_buf.get(&pos_id).unwrap();
         let ind_buf = self.ind_buf.get(&ind_id).unwrap();
+        let range = clamp_range(first, count, ind_buf.len());
+        self.draw_elements(pos_buf, &ind_buf[range], base_vertex, primitive_type, &[SInstance::new(self.model)]);
+    }
 
+    /// Draw vertices `first..first + count` of the position buffer in order,
+    /// without an index buffer.
+    pub fn draw_arrays(&self, pos_id: PosBufId, primitive_type: Primitive, first: usize, count: usize) {
+        let pos_buf = self.pos_buf.get(&pos_id).unwrap();
+        let range = clamp_range(first, count, pos_buf.len());
+        let indices: Vec<u32> = (range.start as u32..range.end as u32).collect();
+        self.draw_elements(pos_buf, &indices, 0, primitive_type, &[SInstance::new(self.model)]);
+    }
 
-        let pvm = self.projection * self.view * self.model;
-        let mut inv_m = self.model.clone();
+    fn draw_elements(&self, pos_buf: &[SVertex], indices: &[u32], base_vertex: i32, primitive_type: Primitive, instances: &[SInstance]) {
+        let mut jobs = Vec::new();
+        for (instance_id, instance) in instances.iter().enumerate() {
+            self.assemble_jobs(pos_buf, indices, base_vertex, primitive_type, instance_id as u32, instance, &mut jobs);
+        }
+        self.rasterize_jobs(&jobs);
+    }
+
+    // run the vertex shader for one instance, then clip, cull and set up its
+    // primitives
+    fn assemble_jobs(&self, pos_buf: &[SVertex], ind_buf: &[u32], base_vertex: i32, primitive_type: Primitive, instance_id: u32, instance: &SInstance, jobs: &mut Vec<RasterJob>) {
+        let projection = self.clip_projection();
+        let mut inv_m = instance.model.clone();
         inv_m.try_inverse_mut();
         inv_m.transpose_mut();
+        let transforms = SVertexTransforms {
+            model: instance.model,
+            view: self.view,
+            projection,
+            pvm: projection * self.view * instance.model,
+            normal_matrix: inv_m,
+        };
+
+        // vertex stage, every vertex the indices use is transformed once and
+        // shared by all the primitives around it. indices that land outside
+        // the position buffer after adding `base_vertex` are dropped together
+        // with the primitives using them.
+        let fetch = |i: u32| {
+            let k = i as i64 + base_vertex as i64;
+            if k >= 0 && k < pos_buf.len() as i64 { Some(k as usize) } else { None }
+        };
+        // the cache covers the fetched vertices from the lowest to the highest
+        let (first, last) = ind_buf.iter().filter_map(|&i| fetch(i))
+            .fold((usize::MAX, 0), |(first, last), k| (usize::min(first, k), usize::max(last, k)));
+        let mut transformed: Vec<Option<ClipVertex>> = vec![None; last.saturating_sub(first) + 1];
+        for &i in ind_buf {
+            let k = match fetch(i) {
+                Some(k) => k,
+                None => continue,
+            };
+            let entry = &mut transformed[k - first];
+            if entry.is_none() {
+                *entry = Some((self.vertex_shader)(&SVertexShaderPayload {
+                    vertex: &pos_buf[k],
+                    instance_id,
+                    instance_attributes: &instance.attributes,
+                    transforms: &transforms,
+                    uniforms: &self.uniforms,
+                }));
+            }
+        }
+        let vertex = |i: u32| fetch(i).and_then(|k| transformed[k - first].as_ref());
+
+        match primitive_type {
+            Primitive::POINT => {
+                for &i in ind_buf {
+                    let v = match vertex(i) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    if !clip::point_inside(&v.pos, self.clip_depth()) {
+                        continue;
+                    }
+                    jobs.push(RasterJob::Point {
+                        center: self.to_screen(&v.pos),
+                        size: f32::max(v.point_size.unwrap_or(self.point_state.size), 1.),
+                        varyings: v.varyings.clone(),
+                        instance_id,
+                    });
+                }
+            }
+            Primitive::LINE | Primitive::LINE_STRIP => {
+                for line in assemble_lines(primitive_type, ind_buf) {
+                    let (v0, v1) = match (vertex(line[0]), vertex(line[1])) {
+                        (Some(v0), Some(v1)) => match clip::clip_line(v0, v1, self.clip_depth()) {
+                            Some(l) => l,
+                            None => continue,
+                        },
+                        _ => continue,
+                    };
+                    jobs.push(RasterJob::Line {
+                        begin: self.to_screen(&v0.pos),
+                        end: self.to_screen(&v1.pos),
+                        width: self.line_state.width,
+                        depth_bias: 0.,
+                        depth_write: true,
+                        shading: LineShading::Shaded { w: [v0.pos.w, v1.pos.w], varyings: [v0.varyings, v1.varyings], instance_id },
+                    });
+                }
+            }
+            Primitive::TRIANGLE | Primitive::TRIANGLE_STRIP | Primitive::TRIANGLE_FAN => {
+                for ind in assemble_triangles(primitive_type, ind_buf) {
+                    let input = match (vertex(ind[0]), vertex(ind[1]), vertex(ind[2])) {
+                        (Some(v0), Some(v1), Some(v2)) => [v0, v1, v2],
+                        _ => continue,
+                    };
+                    // only triangles crossing the frustum make new vertices
+                    let clipped;
+                    let polygon: Vec<&ClipVertex> = if input.iter().all(|v| clip::point_inside(&v.pos, self.clip_depth())) {
+                        input.to_vec()
+                    } else {
+                        clipped = clip::clip_polygon(&input, self.clip_depth());
+                        clipped.iter().collect()
+                    };
+                    if polygon.is_empty() {
+                        continue;
+                    }
+                    // clipping keeps the winding, the area of the whole polygon decides
+                    let screen: Vec<glm::Vec3> = polygon.iter().map(|v| self.to_screen(&v.pos)).collect();
+                    let normal = polygon_normal(&screen);
+                    let front_facing = self.front_face.is_front(normal.z);
+                    if self.cull_mode.culls(front_facing) {
+                        self.culled_count.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
 
-        for ind in ind_buf {
-            let mut v = Vec::new();
-            let mut t = Triangle::new();
+                    let mode = self.polygon_mode;
+                    if mode == PolygonMode::FILL || mode == PolygonMode::FILL_WIREFRAME {
+                        // clipped polygon is convex, split it into a fan
+                        for i in 1..polygon.len() - 1 {
+                            let mut t = self.setup_triangle(&[polygon[0], polygon[i], polygon[i + 1]], &input[0].varyings);
+                            t.instance_id = instance_id;
+                            if let Some(edges) = EdgeFunctions::new(&t.v) {
+                                jobs.push(RasterJob::Triangle { t, edges, front_facing });
+                            }
+                        }
+                    }
+                    if mode != PolygonMode::FILL {
+                        self.push_wire_jobs(&input, &normal, jobs);
+                    }
+                }
+            }
+        }
+    }
 
+    // edges or vertices of a triangle in the LINE, POINT and FILL_WIREFRAME
+    // modes. only the original edges are drawn, not the ones made by clipping.
+    fn push_wire_jobs(&self, input: &[&ClipVertex; 3], normal: &glm::Vec3, jobs: &mut Vec<RasterJob>) {
+        let overlay = self.polygon_mode == PolygonMode::FILL_WIREFRAME;
+        let depth_bias = if overlay { self.wire_depth_bias(normal) } else { 0. };
+        let wire = |begin: glm::Vec3, end: glm::Vec3| RasterJob::Line {
+            begin,
+            end,
+            width: self.wireframe.width,
+            depth_bias,
+            // the overlay must not hide the edges of the triangles behind it
+            depth_write: !overlay,
+            shading: LineShading::Constant(self.wireframe.color),
+        };
 
-            for i in 0..3 {
-                let v4_pos = utility::to_vec4(&pos_buf[ind[i] as usize].pos, None);
-                v.push(pvm * v4_pos);
+        for i in 0..3 {
+            if self.polygon_mode == PolygonMode::POINT {
+                if clip::point_inside(&input[i].pos, self.clip_depth()) {
+                    let p = self.to_screen(&input[i].pos);
+                    jobs.push(wire(p, p));
+                }
+            } else if let Some((v0, v1)) = clip::clip_line(input[i], input[(i + 1) % 3], self.clip_depth()) {
+                jobs.push(wire(self.to_screen(&v0.pos), self.to_screen(&v1.pos)));
             }
+        }
+    }
+
+    // depth offset that keeps the wire in front of its own filled triangle,
+    // scaled by the depth slope like glPolygonOffset, `n` is the screen space
+    // normal of the triangle
+    fn wire_depth_bias(&self, n: &glm::Vec3) -> f32 {
+        let slope = if n.z != 0. { f32::max(n.x.abs(), n.y.abs()) / n.z.abs() } else { 0. };
+        let bias = slope * (0.5 * self.wireframe.width + 1.) + 1e-5;
+        // toward the viewer
+        if self.depth_state.reversed_z { bias } else { -bias }
+    }
 
-            for i in 0..3usize {
-                t.set_perp_pos(i, &v[i]);
+    // bin the primitives into tiles, then rasterize the tiles in parallel.
+    // every tile runs its primitives in submission order, so the result
+    // doesn't depend on the thread count.
+    fn rasterize_jobs(&self, jobs: &[RasterJob]) {
+        if jobs.is_empty() {
+            return;
+        }
+        let mut frame_buf = self.frame_buf.lock().unwrap();
+
+        let mut bins = vec![Vec::new(); frame_buf.tile_count()];
+        for (i, job) in jobs.iter().enumerate() {
+            let (y_min, y_max) = job.y_range();
+            if y_max < 0. || y_min >= self.height as f32 {
+                continue;
+            }
+            let first = frame_buf.tile_of(f32::min(y_max, self.height as f32 - 1.) as i32);
+            let last = frame_buf.tile_of(f32::max(y_min, 0.) as i32);
+            for k in first..=last {
+                bins[k].push(i);
             }
+        }
+
+        let work = frame_buf.tiles_mut().into_iter().zip(bins.into_iter()).filter(|(_, bin)| !bin.is_empty());
+        self.for_each_tile(work, |(mut tile, bin)| self.rasterize_tile(&mut tile, jobs, &bin));
+    }
 
-            for vec in v.iter_mut() {
-                *vec /= vec.w;
+    // hand the tiles to up to `thread_count` threads
+    fn for_each_tile<T, I, F>(&self, work: I, f: F)
+    where
+        T: Send,
+        I: Iterator<Item = T> + Send,
+        F: Fn(T) + Sync,
+    {
+        if self.thread_count <= 1 {
+            work.for_each(f);
+            return;
+        }
+
+        let work = Mutex::new(work);
+        std::thread::scope(|scope| {
+            for _ in 0..self.thread_count {
+                scope.spawn(|| loop {
+                    let next = work.lock().unwrap().next();
+                    match next {
+                        Some(item) => f(item),
+                        None => break,
+                    }
+                });
+            }
+        });
+    }
+
+    /// Render the depth of `draws` from `light` into a `size` x `size`
+    /// shadow map. Every draw has its own instances like `draw_instanced`,
+    /// vertices go through the bound vertex shader and uniforms, the cull
+    /// mode applies. No fragment shader runs. The rest of the state, the
+    /// frame buffer and the culled count are left as they were.
+    pub fn render_shadow_map(&mut self, light: &LightSource, size: u32, draws: &[(PosBufId, IndBufId, Primitive, &[SInstance])]) -> ShadowMap {
+        let mut pass = match self.shadow_pass.take() {
+            Some(pass) if pass.width == size => pass,
+            _ => {
+                let mut pass = Rasterizer::new(size, size);
+                pass.depth_only = true;
+                Box::new(pass)
+            }
+        };
+        self.swap_pass_state(&mut pass);
+
+        let faces = light.views().into_iter().map(|(view, projection)| {
+            self.view = view;
+            self.projection = projection;
+            self.clear(Buffer::DEPTH);
+            for &(pos_id, ind_id, primitive, instances) in draws.iter() {
+                self.draw_instanced(pos_id, ind_id, primitive, instances);
             }
+            ShadowFace {
+                view_projection: projection * view,
+                size,
+                depth: self.frame_buf.get_mut().unwrap().resolved_depth().to_vec(),
+            }
+        }).collect();
+
+        self.swap_pass_state(&mut pass);
+        self.shadow_pass = Some(pass);
+        ShadowMap::new(*light, faces)
+    }
+
+    // exchange the target and the state a depth pass sets with `pass`,
+    // calling it twice puts everything back. the pass counts its own culled
+    // triangles.
+    fn swap_pass_state(&mut self, pass: &mut Rasterizer) {
+        use std::mem::swap;
+        swap(&mut self.frame_buf, &mut pass.frame_buf);
+        swap(&mut self.width, &mut pass.width);
+        swap(&mut self.height, &mut pass.height);
+        swap(&mut self.viewport, &mut pass.viewport);
+        swap(&mut self.scissor, &mut pass.scissor);
+        swap(&mut self.msaa, &mut pass.msaa);
+        swap(&mut self.sample_list, &mut pass.sample_list);
+        swap(&mut self.sample_offsets, &mut pass.sample_offsets);
+        swap(&mut self.view, &mut pass.view);
+        swap(&mut self.projection, &mut pass.projection);
+        swap(&mut self.polygon_mode, &mut pass.polygon_mode);
+        swap(&mut self.depth_state, &mut pass.depth_state);
+        swap(&mut self.stencil_state, &mut pass.stencil_state);
+        swap(&mut self.depth_only, &mut pass.depth_only);
+        swap(&mut self.culled_count, &mut pass.culled_count);
+    }
 
-            for vert in v.iter_mut() {
-                vert.x = 0.5 * (self.width as f32) * (vert.x + 1.0);
-                vert.y = 0.5 * (self.height as f32) * (vert.y + 1.0);
+    /// Deferred shading: run the lighting shader once for every pixel of the
+    /// G-buffer that was drawn and write the result to the color buffer.
+    pub fn lighting_pass(&self) {
+        let mut frame_buf = self.frame_buf.lock().unwrap();
+        let bounds = self.write_bounds();
+        self.for_each_tile(frame_buf.tiles_mut().into_iter(), |mut tile| {
+            for idx in 0..tile.pixel_count() {
+                let (x, y) = tile.coords(idx);
+                if !bounds.contains(x, y) {
+                    continue;
+                }
+                let color = match tile.texel(idx) {
+                    Some(texel) => (self.lighting_shader)(&SLightingShaderPayload {
+                        screen_pos: glm::vec2(x as f32 + 0.5, y as f32 + 0.5),
+                        texel,
+                        uniforms: &self.uniforms,
+                    }),
+                    None => continue,
+                };
+                tile.set_pixel(idx, &to_bgra(&color));
             }
+        });
+    }
 
-            for i in 0..3usize {
-                t.set_vertex(i, &v[i].xyz());
-                let uv = &pos_buf[ind[i] as usize].uv;
-                t.set_tex_coord(i, uv.x, uv.y);
-                t.set_color(i,
-                            pos_buf[ind[i] as usize].color.x,
-                            pos_buf[ind[i] as usize].color.y,
-                            pos_buf[ind[i] as usize].color.z);
-                let v4_normal = utility::to_vec4(&pos_buf[ind[i] as usize].normal, None);
-                let w_normal = (inv_m * v4_normal).xyz().normalize();
-                t.set_normal(i, &w_normal);
-                let v4_pos = utility::to_vec4(&pos_buf[ind[i] as usize].pos, None);
-                t.set_position(i, &(self.model * v4_pos).xyz());
+    fn rasterize_tile(&self, tile: &mut Tile, jobs: &[RasterJob], bin: &[usize]) {
+        for &i in bin {
+            match &jobs[i] {
+                RasterJob::Triangle { t, edges, front_facing } => {
+                    self.rasterize_triangle(t, edges, *front_facing, tile);
+                }
+                RasterJob::Line { begin, end, width, depth_bias, depth_write, shading } => {
+                    self.rasterize_line(begin, end, *width, *depth_bias, *depth_write, shading, tile);
+                }
+                RasterJob::Point { center, size, varyings, instance_id } => {
+                    self.rasterize_point(center, *size, varyings, *instance_id, tile);
+                }
             }
+        }
+    }
 
-            // self.rasterize_wireframe(&t);
-            self.rasterize_triangle(&t);
+    // `provoking` holds the flat varyings of the whole primitive
+    fn setup_triangle(&self, v: &[&ClipVertex; 3], provoking: &Varyings) -> Triangle {
+        let mut t = Triangle::new();
+        for i in 0..3usize {
+            t.set_perp_pos(i, &v[i].pos);
+            t.set_vertex(i, &self.to_screen(&v[i].pos));
+            t.set_varyings(i, &v[i].varyings);
+            t.varyings[i].copy_flat(provoking);
         }
+        t
     }
 
-    fn rasterize_wireframe(&self, t: &Triangle) {
-        self.draw_line(&t.c(), &t.a());
-        self.draw_line(&t.a(), &t.b());
-        self.draw_line(&t.b(), &t.c());
+    // perspective divide and viewport transform, z becomes window depth
+    fn to_screen(&self, clip: &glm::Vec4) -> glm::Vec3 {
+        let ndc = clip / clip.w;
+        let depth = match self.clip_depth() {
+            ClipDepth::REVERSED => ndc.z,
+            ClipDepth::STANDARD => 0.5 * ndc.z + 0.5,
+        };
+        let vp = &self.viewport;
+        glm::vec3(
+            vp.x + 0.5 * vp.width * (ndc.x + 1.0),
+            vp.y + 0.5 * vp.height * (ndc.y + 1.0),
+            vp.min_depth + depth * (vp.max_depth - vp.min_depth),
+        )
     }
 
-    fn rasterize_triangle(&self, t: &Triangle) {
-        // find aabb
-        let mut lb = glm::vec2(self.width as f32, self.height as f32);
-        let mut rt = glm::vec2(0f32, 0f32);
-
-        for _v in &t.v {
-            lb.x = f32::max(f32::min(lb.x, _v.x), 0f32);
-            lb.y = f32::max(f32::min(lb.y, _v.y), 0f32);
-            rt.x = f32::min(f32::max(rt.x, _v.x), self.width as f32);
-            rt.y = f32::min(f32::max(rt.y, _v.y),  self.height as f32);
-        }
-        
-        let lb = glm::vec2(lb.x as i32, lb.y as i32);
-        let rt = glm::vec2(rt.x as i32, rt.y as i32);
-        
-        let perp_pos = &t.perp_pos;
-
-        let sample_list = &SAMPLE_LIST;
-
-        for x in lb.x .. rt.x {
-            for y in lb.y .. rt.y {
-                if !inside_triangle(x as f32, y as f32, &t.v) {
+    fn clip_depth(&self) -> ClipDepth {
+        if self.depth_state.reversed_z { ClipDepth::REVERSED } else { ClipDepth::STANDARD }
+    }
+
+    // the projection with reversed depth applied. z is replaced by
+    // (w - z) / 2 in the matrix, so near lands on w and far on 0 before the
+    // divide. far surfaces get depths close to 0 where floats are dense,
+    // instead of being rounded close to 1 first.
+    fn clip_projection(&self) -> glm::Mat4 {
+        let mut projection = self.projection;
+        if self.depth_state.reversed_z {
+            let z = (projection.row(3) - projection.row(2)) * 0.5;
+            projection.set_row(2, &z);
+        }
+        projection
+    }
+
+    // frame buffer area that may be written, limited by the scissor rect
+    fn write_bounds(&self) -> Rect {
+        let full = Rect::new(0, 0, self.width, self.height);
+        match &self.scissor {
+            Some(rect) => full.intersect(rect),
+            None => full,
+        }
+    }
+
+    // depth is affine in screen space, the varyings are interpolated
+    // perspective correct. lines use the front stencil face.
+    fn rasterize_line(&self, begin: &glm::Vec3, end: &glm::Vec3, width: f32, depth_bias: f32, depth_write: bool, shading: &LineShading, tile: &mut Tile) {
+        let antialiased = self.line_state.antialiased;
+        let blend = if antialiased && !self.blend_state.enable { BlendState::alpha_blending() } else { self.blend_state };
+        let d = (end - begin).xy();
+        let len2 = d.norm_squared();
+        // change of t per pixel step, for the derivatives
+        let (dtdx, dtdy) = if len2 > 0. { (d.x / len2, d.y / len2) } else { (0., 0.) };
+
+        let shade = |t: f32| match shading {
+            LineShading::Shaded { varyings, w, instance_id } => {
+                let varyings_at = |t: f32| {
+                    // screen space t to the clip space one
+                    let t = t / w[1] / ((1. - t) / w[0] + t / w[1]);
+                    Varyings::lerp(&varyings[0], &varyings[1], t, w[0], w[1])
+                };
+                let v = varyings_at(t);
+                let ddx = varyings_at(t + dtdx).difference(&v);
+                let ddy = varyings_at(t + dtdy).difference(&v);
+                (self.frame_shader)(&SFragmentShaderPayload {
+                    varyings: v,
+                    ddx: &ddx,
+                    ddy: &ddy,
+                    point_coord: glm::zero(),
+                    instance_id: *instance_id,
+                    uniforms: &self.uniforms,
+                })
+            }
+            LineShading::Constant(color) => *color,
+        };
+
+        let bounds = self.write_bounds();
+        let rows = (tile.y_min, tile.y_max);
+        for_each_line_pixel(begin, end, width, antialiased, &bounds, rows, |x, y, t, coverage| {
+            let depth = glm::lerp_scalar(begin.z, end.z, t) + depth_bias;
+            let idx = tile.index(x, y);
+            let mut color = None;
+            let mut pass = false;
+            for s_idx in 0..self.sample_list.len() {
+                if !self.depth_stencil_test(tile, s_idx, idx, depth, depth_write, true) {
                     continue;
                 }
-                let idx = self.get_index(x, y) as usize;
-                for s_idx in 0..sample_list.len() {
-                    let _x = x as f32 + sample_list[s_idx].0;
-                    let _y = y as f32 + sample_list[s_idx].1;
-                    let (alpha, beta, gamma) = compute_barycentric_2d(_x, _y, &t.v);
-                    let barycentric = glm::vec3(alpha, beta, gamma);
-                    let z_reciprocal = alpha / perp_pos[0].w + beta / perp_pos[1].w + gamma / perp_pos[2].w;
-                    let z_interpolated = 1f32 / z_reciprocal;
-
-                    // z test
-                    if z_interpolated >= self.depth_bufs[s_idx].borrow_mut()[idx] {
-                        continue
-                    }
+                pass = true;
+                if self.depth_only {
+                    continue;
+                }
+                // shaded once per pixel, on the first sample that passes
+                let color = color.get_or_insert_with(|| {
+                    let mut color = shade(t);
+                    color.w *= coverage;
+                    color
+                });
+                self.blend_color(&blend, tile, s_idx, idx, color);
+            }
+            if pass {
+                tile.resolve(idx);
+            }
+        });
+    }
 
-                    // interpolated color
-                    let color_interpolated = interpolated_value(
-                        &t.color, z_interpolated, &barycentric, &t.perp_pos
-                    );
-                    let normal_interpolated = interpolated_value(
-                        &t.normal, z_interpolated, &barycentric, &t.perp_pos
-                    );
-                    let tex_coord_interpolated = interpolated_value(
-                        &t.tex_coords, z_interpolated, &barycentric, &t.perp_pos
-                    );
-                    let position_interpolated = interpolated_value(
-                        &t.position, z_interpolated, &barycentric, &t.perp_pos
-                    );
-
-                    if color_interpolated[0] > 1. {
-                        println!("{:?}, {:?}", color_interpolated, z_interpolated);
+    // screen aligned sprite of `size` pixels, covers the pixels whose center
+    // is inside. one depth for the whole point, points use the front stencil
+    // face.
+    fn rasterize_point(&self, center: &glm::Vec3, size: f32, varyings: &Varyings, instance_id: u32, tile: &mut Tile) {
+        let half = 0.5 * size;
+        let bounds = self.write_bounds();
+        let x0 = i32::max((center.x - half).floor() as i32, bounds.x);
+        let x1 = i32::min((center.x + half).ceil() as i32, bounds.x + bounds.width as i32 - 1);
+        let y0 = i32::max((center.y - half).floor() as i32, i32::max(bounds.y, tile.y_min));
+        let y1 = i32::min((center.y + half).ceil() as i32, i32::min(bounds.y + bounds.height as i32, tile.y_max) - 1);
+
+        // the varyings do not change across the point
+        let none = Varyings::new();
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let offset = glm::vec2(x as f32 + 0.5, y as f32 + 0.5) - center.xy();
+                let covered = if self.point_state.round {
+                    offset.norm() <= half
+                } else {
+                    // half open so odd sizes on pixel centers cover size pixels
+                    -half < offset.x && offset.x <= half && -half < offset.y && offset.y <= half
+                };
+                if !covered {
+                    continue;
+                }
+
+                let idx = tile.index(x, y);
+                let mut color = None;
+                let mut pass = false;
+                for s_idx in 0..self.sample_list.len() {
+                    if !self.depth_stencil_test(tile, s_idx, idx, center.z, true, true) {
+                        continue;
+                    }
+                    pass = true;
+                    if self.depth_only {
+                        continue;
                     }
+                    let color = color.get_or_insert_with(|| (self.frame_shader)(&SFragmentShaderPayload {
+                        varyings: varyings.clone(),
+                        ddx: &none,
+                        ddy: &none,
+                        // screen y is up
+                        point_coord: glm::vec2(offset.x / size + 0.5, 0.5 - offset.y / size),
+                        instance_id,
+                        uniforms: &self.uniforms,
+                    }));
+                    self.write_color(tile, s_idx, idx, color);
+                }
+                if pass {
+                    tile.resolve(idx);
+                }
+            }
+        }
+    }
 
-                    let fs_payload = SFragmentShaderPayload {
-                        eye_pos: self.cfv_eye_pos.clone(),
-                        position: position_interpolated,
-                        color: color_interpolated,
-                        normal: normal_interpolated,
-                        tex_coords: tex_coord_interpolated.xy(),
+    fn rasterize_triangle(&self, t: &Triangle, edges: &EdgeFunctions, front_facing: bool, tile: &mut Tile) {
+        // find aabb
+        let (lb, rt) = edges.bounding_box();
+        let bounds = self.write_bounds();
+        let lb = glm::vec2(i32::max(lb.x, bounds.x), i32::max(i32::max(lb.y, bounds.y), tile.y_min));
+        let rt = glm::vec2(
+            i32::min(rt.x, bounds.x + bounds.width as i32 - 1),
+            i32::min(i32::min(rt.y, bounds.y + bounds.height as i32 - 1), tile.y_max - 1),
+        );
+        if lb.x > rt.x || lb.y > rt.y {
+            return;
+        }
 
-                        texture: self.cfv_texture0.clone(),
-                    };
+        // window depth is affine in screen space
+        let depth = glm::vec3(t.v[0].z, t.v[1].z, t.v[2].z);
+
+        // blocks of the aabb that may pass the depth test
+        let block_lb = glm::vec2(lb.x / HIZ_BLOCK as i32, (tile.y_max - 1 - rt.y) / HIZ_BLOCK as i32);
+        let block_cols = (rt.x / HIZ_BLOCK as i32 - block_lb.x + 1) as usize;
+        let mut visible = Vec::new();
+        if self.hierarchical_z_usable(front_facing) {
+            let (min, max) = (depth.min(), depth.max());
+            for row in 0..=((tile.y_max - 1 - lb.y) / HIZ_BLOCK as i32 - block_lb.y) {
+                let y = tile.y_max - 1 - (block_lb.y + row) * HIZ_BLOCK as i32;
+                for col in 0..block_cols as i32 {
+                    let block = tile.block_of((block_lb.x + col) * HIZ_BLOCK as i32, y);
+                    let (stored_min, stored_max) = tile.depth_bounds(block);
+                    visible.push(self.depth_state.func.may_pass(min, max, stored_min, stored_max));
+                }
+            }
+            if !visible.iter().any(|&v| v) {
+                // the whole triangle is hidden in this tile
+                return;
+            }
+        }
+
+        let sample_list = &self.sample_list;
+        let sample_count = sample_list.len();
+        let mut quads = QuadCache::new(lb.x, rt.x);
+        let step_x = edges.step_x();
+        // edge values of every sample, stepped along the row
+        let mut sample_edges = vec![[0i64; 3]; sample_count];
+
+        for y in lb.y ..= rt.y {
+            for (s_idx, &(sx, sy)) in self.sample_offsets.iter().enumerate() {
+                sample_edges[s_idx] = edges.evaluate(
+                    lb.x as i64 * SUBPIXEL_ONE + sx,
+                    y as i64 * SUBPIXEL_ONE + sy,
+                );
+            }
 
-                    // run frame shader
-                    let color = (self.frame_shader)(&fs_payload);
-                    self.frame_bufs[s_idx].borrow_mut()[idx] = color.zyx(); // rgb -> bgr
-                    // z write
-                    self.depth_bufs[s_idx].borrow_mut()[idx] = z_interpolated;
+            let block_row = ((tile.y_max - 1 - y) / HIZ_BLOCK as i32 - block_lb.y) as usize;
+            for x in lb.x ..= rt.x {
+                let mut coverage = 0u32;
+                let block_col = (x / HIZ_BLOCK as i32 - block_lb.x) as usize;
+                if visible.is_empty() || visible[block_row * block_cols + block_col] {
+                    for s_idx in 0..sample_count {
+                        if edges.inside(&sample_edges[s_idx]) {
+                            coverage |= 1 << s_idx;
+                        }
+                    }
                 }
 
-                // 合并各buffer信息
-                let mut merge_frame_buf = self.frame_bufs[(self.msaa) as usize].borrow_mut();
-                let mut merge_depth_buf = self.depth_bufs[(self.msaa) as usize].borrow_mut();
-                merge_frame_buf[idx] = glm::zero();
-                merge_depth_buf[idx] = 0f32;
-                for s_idx in 0..self.msaa as usize {
-                    merge_frame_buf[idx] += self.frame_bufs[s_idx].borrow_mut()[idx] / self.msaa as f32;
-                    merge_depth_buf[idx] += self.depth_bufs[s_idx].borrow_mut()[idx] / self.msaa as f32;
+                if coverage != 0 {
+                    let idx = tile.index(x, y);
+                    let (ddx, ddy) = quads.get(x, y, || self.quad_derivatives(t, edges, x, y));
+
+                    match self.aa_mode {
+                        // the G-buffer has one texel per pixel, deferred always shades like msaa
+                        AntiAliasing::SSAA if self.shading_mode == ShadingMode::FORWARD && !self.depth_only => {
+                            // shade every sample
+                            for s_idx in 0..sample_count {
+                                if coverage & (1 << s_idx) == 0 {
+                                    continue;
+                                }
+                                let barycentric = edges.barycentric(&sample_edges[s_idx]);
+
+                                // stencil and z test
+                                if !self.depth_stencil_test(tile, s_idx, idx, barycentric.dot(&depth), true, front_facing) {
+                                    continue
+                                }
+
+                                // run frame shader
+                                let color = self.shade_fragment(t, &barycentric, ddx, ddy);
+                                self.write_color(tile, s_idx, idx, &color);
+                            }
+                        }
+                        _ => {
+                            // z test per sample, shade once per pixel
+                            let mut pass = 0u32;
+                            let mut centroid = glm::vec2(0f32, 0f32);
+                            for s_idx in 0..sample_count {
+                                if coverage & (1 << s_idx) == 0 {
+                                    continue;
+                                }
+                                centroid += glm::vec2(x as f32 + sample_list[s_idx].0, y as f32 + sample_list[s_idx].1);
+
+                                let sample_depth = edges.barycentric(&sample_edges[s_idx]).dot(&depth);
+                                if !self.depth_stencil_test(tile, s_idx, idx, sample_depth, true, front_facing) {
+                                    continue
+                                }
+                                pass |= 1 << s_idx;
+                            }
+
+                            if pass != 0 && !self.depth_only {
+                                // centroid of the covered samples, pixel center when fully covered
+                                let centroid = if coverage.count_ones() as usize == sample_count {
+                                    glm::vec2(x as f32 + 0.5, y as f32 + 0.5)
+                                } else {
+                                    centroid / coverage.count_ones() as f32
+                                };
+                                let barycentric = edges.barycentric_at(centroid.x, centroid.y);
+                                if self.shading_mode == ShadingMode::DEFERRED {
+                                    let texel = (self.gbuffer_shader)(&self.fragment_payload(t, &barycentric, ddx, ddy));
+                                    tile.set_texel(idx, texel);
+                                } else {
+                                    let color = self.shade_fragment(t, &barycentric, ddx, ddy);
+                                    for s_idx in 0..sample_count {
+                                        if pass & (1 << s_idx) == 0 {
+                                            continue;
+                                        }
+                                        self.write_color(tile, s_idx, idx, &color);
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    tile.resolve(idx);
+                }
+
+                for e in sample_edges.iter_mut() {
+                    for i in 0..3 {
+                        e[i] += step_x[i];
+                    }
                 }
             }
         }
-    }   
+    }
 
-    fn draw_line(&self, begin: &glm::Vec3, end: &glm::Vec3) {
-        utility::draw_line(
-            begin,
-            end,
-            Box::new(|point: &glm::Vec3, color: &glm::Vec3| self.set_pixel(point, color)),
-        );
+    // hierarchical z may skip fragments only if they would change nothing,
+    // a stencil op on a failed test still has to run
+    fn hierarchical_z_usable(&self, front_facing: bool) -> bool {
+        if !self.hierarchical_z {
+            return false;
+        }
+        let stencil_state = &self.stencil_state;
+        if !stencil_state.enable {
+            return true;
+        }
+        let face = stencil_state.face(front_facing);
+        face.fail_op == StencilOp::KEEP && face.depth_fail_op == StencilOp::KEEP
+    }
+
+    // stencil test, then depth test. updates the stencil and, with
+    // `depth_write`, the depth of the sample. returns whether the sample gets
+    // shaded.
+    // runs before the frame shader (early z): shaders can't write depth or
+    // discard, so this is always the same as testing after shading.
+    fn depth_stencil_test(&self, tile: &mut Tile, sample: usize, idx: usize, depth: f32, depth_write: bool, front_facing: bool) -> bool {
+        let stencil_state = &self.stencil_state;
+        if stencil_state.enable {
+            let face = stencil_state.face(front_facing);
+            let stored = tile.stencil(sample, idx);
+            if !stencil_state.test(face, stored) {
+                tile.set_stencil(sample, idx, stencil_state.update(face.fail_op, stored));
+                return false;
+            }
+            let depth_pass = self.depth_state.func.test(depth, tile.depth(sample, idx));
+            let op = if depth_pass { face.pass_op } else { face.depth_fail_op };
+            tile.set_stencil(sample, idx, stencil_state.update(op, stored));
+            if !depth_pass {
+                return false;
+            }
+        } else if !self.depth_state.func.test(depth, tile.depth(sample, idx)) {
+            return false;
+        }
+
+        // z write
+        if depth_write && self.depth_state.write {
+            tile.set_depth(sample, idx, depth);
+        }
+        true
+    }
+
+    // blend an rgba shader output into a bgra sample
+    fn write_color(&self, tile: &mut Tile, sample: usize, idx: usize, color: &glm::Vec4) {
+        self.blend_color(&self.blend_state, tile, sample, idx, color);
     }
 
-    fn get_index(&self, row: i32, col: i32) -> usize {
-        assert!(row >= 0 && row < self.width as i32);
-        assert!(col >= 0 && col < self.height as i32);
+    fn blend_color(&self, blend: &BlendState, tile: &mut Tile, sample: usize, idx: usize, color: &glm::Vec4) {
+        let dst = to_bgra(&tile.color(sample, idx));
+        tile.set_color(sample, idx, &to_bgra(&blend.blend(color, &dst)));
+    }
 
-        ((self.height - col as u32) * self.width + row as u32) as usize
+    // interpolate the triangle attributes at `barycentric`
+    fn fragment_payload<'a>(&'a self, t: &Triangle, barycentric: &glm::Vec3, ddx: &'a Varyings, ddy: &'a Varyings) -> SFragmentShaderPayload<'a> {
+        SFragmentShaderPayload {
+            varyings: Varyings::interpolate(&t.varyings, barycentric, &perp_w(t)),
+            ddx,
+            ddy,
+            point_coord: glm::zero(),
+            instance_id: t.instance_id,
+            uniforms: &self.uniforms,
+        }
+    }
+
+    fn shade_fragment(&self, t: &Triangle, barycentric: &glm::Vec3, ddx: &Varyings, ddy: &Varyings) -> glm::Vec4 {
+        (self.frame_shader)(&self.fragment_payload(t, barycentric, ddx, ddy))
+    }
+
+    // coarse derivatives of the varyings, the same for the whole 2x2 quad of
+    // pixel (x, y). the varyings at the quad corners are extrapolated from
+    // the barycentrics, also outside the triangle. fragments are still shaded
+    // one at a time, no helper invocations run.
+    fn quad_derivatives(&self, t: &Triangle, edges: &EdgeFunctions, x: i32, y: i32) -> (Varyings, Varyings) {
+        let (qx, qy) = ((x & !1) as f32 + 0.5, (y & !1) as f32 + 0.5);
+        let w = perp_w(t);
+        let at = |x: f32, y: f32| Varyings::interpolate(&t.varyings, &edges.barycentric_at(x, y), &w);
+        let origin = at(qx, qy);
+        (at(qx + 1., qy).difference(&origin), at(qx, qy + 1.).difference(&origin))
     }
 
     fn get_next_id(&mut self) -> u32 {
@@ -454,3 +1407,805 @@ impl Rasterizer {
 
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_primitives() {
+        let ind = [0u32, 1, 2, 3, 4];
+
+        assert_eq!(assemble_lines(Primitive::LINE, &ind), vec![[0, 1], [2, 3]]);
+        assert_eq!(assemble_lines(Primitive::LINE_STRIP, &ind), vec![[0, 1], [1, 2], [2, 3], [3, 4]]);
+
+        assert_eq!(assemble_triangles(Primitive::TRIANGLE, &ind), vec![[0, 1, 2]]);
+        assert_eq!(
+            assemble_triangles(Primitive::TRIANGLE_STRIP, &ind),
+            vec![[0, 1, 2], [2, 1, 3], [2, 3, 4]]
+        );
+        assert_eq!(
+            assemble_triangles(Primitive::TRIANGLE_FAN, &ind),
+            vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]
+        );
+    }
+
+    fn render_test_scene(rst: &mut Rasterizer) -> Vec<glm::Vec4> {
+        let mut pos = Vec::new();
+        for i in 0..12 {
+            let a = i as f32 * 0.5;
+            let color = glm::vec3(i as f32 / 12., 1. - i as f32 / 12., 0.5);
+            for &(x, y) in [(a.cos(), a.sin()), (-a.sin(), a.cos()), (0.1 * a, -0.3)].iter() {
+                pos.push(SVertex {
+                    pos: glm::vec3(x, y, -0.1 * i as f32),
+                    color,
+                    ..Default::default()
+                });
+            }
+        }
+        let ind = (0..pos.len() as u32).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(ind);
+
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), Some(0.6))));
+        rst.set_blend_state(BlendState::alpha_blending());
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, ind_id, Primitive::TRIANGLE);
+        rst.draw(pos_id, ind_id, Primitive::LINE_STRIP);
+        rst.frame_buf.lock().unwrap().resolved_color().to_vec()
+    }
+
+    #[test]
+    fn test_aa_shading_rate() {
+        use std::sync::Arc;
+
+        // white triangle inside the screen, its right edge runs down pixel
+        // column 4 at x = 4.3. it is not clipped, a fan of clipped triangles
+        // would shade the pixels on their shared edges twice.
+        let triangle: Vec<SVertex> = [(-1., -1.), (0.075, -1.), (0.075, 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            color: glm::vec3(1., 1., 1.),
+            ..Default::default()
+        }).collect();
+        let patterns = vec![
+            SamplePattern::GRID,
+            SamplePattern::ROTATED_GRID,
+            SamplePattern::STANDARD,
+            SamplePattern::CUSTOM(vec![(0.1, 0.5), (0.2, 0.5), (0.25, 0.5), (0.9, 0.5)]),
+        ];
+        let mut images = Vec::new();
+        for pattern in patterns {
+            let covered = sample_positions(4, &pattern).unwrap().iter().filter(|s| s.0 < 0.3).count() as u32;
+            for &mode in [AntiAliasing::MSAA, AntiAliasing::SSAA].iter() {
+                let mut rst = Rasterizer::new_with_msaa(8, 8, 4, pattern.clone()).unwrap();
+                // a bad pattern is refused and keeps the current one
+                assert_eq!(rst.set_msaa(3, SamplePattern::GRID), Err(SampleError::UNSUPPORTED_COUNT(3)));
+                assert_eq!(rst.msaa(), 4);
+                rst.set_aa_mode(mode);
+                let pos_id = rst.load_position(triangle.clone());
+                let ind_id = rst.load_index_list(vec![0, 1, 2]);
+                let invocations = Arc::new(AtomicU32::new(0));
+                let counter = invocations.clone();
+                rst.set_frame_shader(Box::new(move |payload: &SFragmentShaderPayload| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    utility::to_vec4(&payload.color(), None)
+                }));
+                rst.clear(Buffer::COLOR | Buffer::DEPTH);
+                rst.draw(pos_id, ind_id, Primitive::TRIANGLE);
+
+                // the color is constant, so the resolved value is the covered
+                // fraction of the pixel
+                let color = rst.frame_buf.lock().unwrap().resolved_color().to_vec();
+                let expected = match mode {
+                    AntiAliasing::MSAA => color.iter().filter(|c| c.x > 0.).count() as u32,
+                    AntiAliasing::SSAA => color.iter().map(|c| (c.x * 4.).round() as u32).sum(),
+                };
+                assert_eq!(invocations.load(Ordering::Relaxed), expected, "{:?} {:?}", pattern, mode);
+                // below the top row the diagonal edge stays left of column 4
+                for y in 1..8 {
+                    assert!((color[y * 8 + 4].x - covered as f32 / 4.).abs() < 1e-6, "{:?} {:?}", pattern, mode);
+                    assert_eq!(color[y * 8 + 5].x, 0.);
+                }
+                images.push(color);
+            }
+            // same image from both modes
+            assert!(images[0] == images[1]);
+            images.clear();
+        }
+    }
+
+    #[test]
+    fn test_multithread_matches_single_thread() {
+        let mut single = Rasterizer::new_with_msaa(97, 83, 4, SamplePattern::ROTATED_GRID).unwrap();
+        single.set_thread_count(1);
+        let mut multi = Rasterizer::new_with_msaa(97, 83, 4, SamplePattern::ROTATED_GRID).unwrap();
+        multi.set_thread_count(4);
+
+        let expected = render_test_scene(&mut single);
+        assert!(expected.iter().any(|c| c.xyz() != glm::Vec3::zeros()));
+        assert!(render_test_scene(&mut multi) == expected);
+    }
+
+    #[test]
+    fn test_stencil_mask() {
+        let mut rst = Rasterizer::new(32, 32);
+        rst.set_thread_count(1);
+        let quad = |x0: f32, y0: f32, x1: f32, y1: f32| vec![
+            SVertex { pos: glm::vec3(x0, y0, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() },
+            SVertex { pos: glm::vec3(x1, y0, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() },
+            SVertex { pos: glm::vec3(x1, y1, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() },
+            SVertex { pos: glm::vec3(x0, y1, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() },
+        ];
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        let mask_id = rst.load_position(quad(-0.5, -0.5, 0.5, 0.5));
+        let full_id = rst.load_position(quad(-1., -1., 1., 1.));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        rst.set_depth_state(DepthState { func: CompareFunc::ALWAYS, write: false, ..Default::default() });
+        rst.clear(Buffer::COLOR | Buffer::DEPTH | Buffer::STENCIL);
+
+        // write 1 into the stencil where the small quad is, no color
+        let write = StencilFace { func: CompareFunc::ALWAYS, pass_op: StencilOp::REPLACE, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(write, 1));
+        rst.set_blend_state(BlendState { enable: true, src_color: BlendFactor::ZERO, dst_color: BlendFactor::ONE, ..Default::default() });
+        rst.draw(mask_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        // full screen quad only where the stencil is 1
+        let read = StencilFace { func: CompareFunc::EQUAL, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(read, 1));
+        rst.set_blend_state(BlendState::default());
+        rst.draw(full_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let lit = frame_buf.resolved_color().iter().filter(|c| c.x == 1.).count();
+        assert_eq!(lit, 16 * 16);
+    }
+
+    #[test]
+    fn test_face_culling_after_clipping() {
+        let mut rst = Rasterizer::new(32, 32);
+        let vertex = |x: f32, y: f32| SVertex { pos: glm::vec3(x, y, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() };
+        // counter clockwise. the first vertex lies on the left plane and the
+        // second is outside of it, so the clipper emits the first one twice.
+        let pos_id = rst.load_position(vec![vertex(-1., 0.), vertex(-2., -1.), vertex(0.5, 0.5)]);
+        let ind_id = rst.load_index_list(vec![0, 1, 2]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+
+        for &front_face in [FrontFace::CCW, FrontFace::CW].iter() {
+            for &cull_mode in [CullMode::NONE, CullMode::BACK, CullMode::FRONT, CullMode::FRONT_AND_BACK].iter() {
+                rst.set_front_face(front_face);
+                rst.set_cull_mode(cull_mode);
+                rst.reset_culled_count();
+                rst.clear(Buffer::COLOR | Buffer::DEPTH);
+                rst.draw(pos_id, ind_id, Primitive::TRIANGLE);
+
+                let culled = cull_mode.culls(front_face == FrontFace::CCW);
+                assert_eq!(rst.culled_count(), culled as u32, "{:?} {:?}", front_face, cull_mode);
+                let lit = rst.frame_buf.lock().unwrap().resolved_color().iter().filter(|c| c.x == 1.).count();
+                assert_eq!(lit > 0, !culled, "{:?} {:?}", front_face, cull_mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stencil_masks_lines() {
+        let mut rst = Rasterizer::new(32, 32);
+        let vertex = |x: f32, y: f32| SVertex { pos: glm::vec3(x, y, 0.), color: glm::vec3(1., 1., 1.), ..Default::default() };
+        let mask_id = rst.load_position(vec![vertex(-1., -1.), vertex(0., -1.), vertex(0., 1.), vertex(-1., 1.)]);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        let line_id = rst.load_position(vec![vertex(-1., 0.01), vertex(1., 0.01)]);
+        let line_ind_id = rst.load_index_list(vec![0, 1]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH | Buffer::STENCIL);
+
+        // stencil 1 on the left half, no color
+        let write = StencilFace { func: CompareFunc::ALWAYS, pass_op: StencilOp::REPLACE, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(write, 1));
+        rst.set_blend_state(BlendState { enable: true, src_color: BlendFactor::ZERO, dst_color: BlendFactor::ONE, ..Default::default() });
+        rst.draw(mask_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        // the line only outside of it, and it increments the stencil it passes
+        let outline = StencilFace { func: CompareFunc::NOT_EQUAL, pass_op: StencilOp::INCR, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(outline, 1));
+        rst.set_blend_state(BlendState::default());
+        rst.clear(Buffer::DEPTH);
+        rst.draw(line_id, line_ind_id, Primitive::LINE);
+
+        let mut frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        assert!((0..32).all(|x| (color[15 * 32 + x].x == 1.) == (x >= 16)));
+        // incremented once where the line passed, the mask is kept
+        let k = frame_buf.tile_of(16);
+        let tile = frame_buf.tile_mut(k);
+        assert_eq!(tile.stencil(0, tile.index(20, 16)), 1);
+        assert_eq!(tile.stencil(0, tile.index(4, 16)), 1);
+    }
+
+    #[test]
+    fn test_viewport_and_scissor() {
+        let mut rst = Rasterizer::new(64, 32);
+        let pos = [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            color: glm::vec3(1., 1., 1.),
+            ..Default::default()
+        }).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+
+        // right half of the screen, top half of that cut by the scissor
+        rst.set_viewport(32., 0., 32., 32., 0., 1.);
+        rst.set_scissor(Some(Rect::new(0, 16, 64, 16)));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        // top row first
+        for y in 0..32 {
+            for x in 0..64 {
+                let expected = x >= 32 && y < 16;
+                assert_eq!(color[y * 64 + x].x == 1., expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wireframe_overlay() {
+        let mut rst = Rasterizer::new(32, 32);
+        let quad = |x0: f32, x1: f32, z: f32, color: glm::Vec3| [(x0, -1f32), (x1, -1.), (x1, 1.), (x0, 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, z),
+            color,
+            ..Default::default()
+        }).collect();
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        let blocker_id = rst.load_position(quad(-1., 0., -0.5, glm::vec3(0., 0., 1.)));
+        let full_id = rst.load_position(quad(-1., 1., 0., glm::vec3(0., 1., 0.)));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        rst.set_wireframe(WireframeState { color: glm::vec4(1., 0., 0., 1.), width: 1. });
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+
+        // the left half is covered by a nearer quad
+        rst.draw(blocker_id, ind_id, Primitive::TRIANGLE_FAN);
+        rst.set_polygon_mode(PolygonMode::FILL_WIREFRAME);
+        rst.draw(full_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        // bgra
+        let red = |x: usize, y: usize| color[y * 32 + x] == glm::vec4(0., 0., 1., 1.);
+        // the diagonal passes the depth test against its own surface
+        assert!((16..32).all(|x| red(x, 31 - x)));
+        // but not against the nearer quad
+        assert!((0..16).all(|x| (0..32).all(|y| !red(x, y))));
+        // the rest of the right half is filled
+        assert_eq!(color[8 * 32 + 16 + 4], glm::vec4(0., 1., 0., 1.));
+    }
+
+    #[test]
+    fn test_line_depth_and_color() {
+        let mut rst = Rasterizer::new(32, 32);
+        let vertex = |x: f32, y: f32, z: f32, color: glm::Vec3| SVertex { pos: glm::vec3(x, y, z), color, ..Default::default() };
+        let red = glm::vec3(1., 0., 0.);
+        let blue = glm::vec3(0., 0., 1.);
+        // left half covered by a nearer quad
+        let blocker_id = rst.load_position([(-1f32, -1f32), (0., -1.), (0., 1.), (-1., 1.)].iter().map(|&(x, y)| vertex(x, y, -0.5, glm::vec3(0., 1., 0.))).collect());
+        let quad_ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        let line_id = rst.load_position(vec![vertex(-1., 0., 0., red), vertex(1., 0., 0., blue)]);
+        let line_ind_id = rst.load_index_list(vec![0, 1]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(blocker_id, quad_ind_id, Primitive::TRIANGLE_FAN);
+        rst.draw(line_id, line_ind_id, Primitive::LINE);
+        {
+            let frame_buf = rst.frame_buf.lock().unwrap();
+            let color = frame_buf.resolved_color();
+            // the line lies on the edge between rows 15 and 16, only the upper one is drawn
+            assert!((0..16).all(|x| color[15 * 32 + x] == glm::vec4(0., 1., 0., 1.)));
+            assert!((16..32).all(|x| color[16 * 32 + x] == glm::vec4(0., 0., 0., 1.)));
+            // bgra, red fades into blue
+            let c = color[15 * 32 + 24];
+            assert!((c - glm::vec4(24.5 / 32., 0., 7.5 / 32., 1.)).norm() < 1e-5, "{:?}", c);
+        }
+
+        // antialiased, half of the coverage on both rows
+        rst.set_line_state(LineState { width: 1., antialiased: true });
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(line_id, line_ind_id, Primitive::LINE);
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        for row in [15, 16].iter() {
+            let c = color[row * 32 + 24];
+            assert!((c.xyz() - glm::vec3(24.5 / 32., 0., 7.5 / 32.) * 0.5).norm() < 1e-5, "{:?}", c);
+        }
+        assert_eq!(color[14 * 32 + 24], glm::vec4(0., 0., 0., 1.));
+    }
+
+    #[test]
+    fn test_out_of_range_writes() {
+        let rst = Rasterizer::new(32, 32);
+        let white = glm::vec3(1f32, 1., 1.);
+        for p in [(32f32, 0f32), (0., 32.), (-1., 0.), (f32::NAN, 5.), (f32::INFINITY, 5.)].iter() {
+            rst.set_pixel(&glm::vec3(p.0, p.1, 0.), &white);
+        }
+        rst.draw_line(&glm::vec3(0., f32::NEG_INFINITY, 0.), &glm::vec3(5., 5., 0.), &white);
+        rst.draw_line(&glm::vec3(-50., 40., 0.), &glm::vec3(50., 40., 0.), &white);
+        assert!(rst.frame_buf.lock().unwrap().resolved_color().iter().all(|c| c.x == 0.));
+
+        // only the visible part of the diagonal is walked
+        rst.draw_line(&glm::vec3(-1e6, -1e6, 0.), &glm::vec3(1e6, 1e6, 0.), &white);
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        assert_eq!(frame_buf.resolved_color().iter().filter(|c| c.x == 1.).count(), 32);
+    }
+
+    #[test]
+    fn test_point_sprites() {
+        let mut rst = Rasterizer::new(32, 32);
+        // uv.x is the point size, color.z tells the points apart
+        let point = |x: f32, y: f32, z: f32, size: f32, id: f32| SVertex {
+            pos: glm::vec3(x, y, z),
+            uv: glm::vec3(size, 0., 0.),
+            color: glm::vec3(0., 0., id),
+            ..Default::default()
+        };
+        let pos_id = rst.load_position(vec![
+            point(-0.5, -0.5, 0., 0., 1.),
+            point(0.5, -0.5, -0.5, 8., 0.5),
+            // behind the square one
+            point(0.5, -0.5, 0., 6., 0.25),
+            point(0.5, 0.5, 0., 6., 0.75),
+        ]);
+        let square_id = rst.load_index_list(vec![0, 1]);
+        let round_id = rst.load_index_list(vec![2, 3]);
+        rst.set_vertex_shader(Box::new(|payload: &SVertexShaderPayload| {
+            let mut out = default_vs(payload);
+            let size = payload.vertex.uv.x;
+            out.point_size = if size > 0. { Some(size) } else { None };
+            out
+        }));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| {
+            glm::vec4(payload.point_coord.x, payload.point_coord.y, payload.color().z, 1.)
+        }));
+        rst.set_point_state(PointState { size: 4., round: false });
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, square_id, Primitive::POINT);
+        rst.set_point_state(PointState { size: 4., round: true });
+        rst.draw(pos_id, round_id, Primitive::POINT);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        // bgra
+        let color = frame_buf.resolved_color();
+        let count = |id: f32| color.iter().filter(|c| c.x == id).count();
+        assert_eq!(count(1.), 4 * 4);
+        assert_eq!(count(0.5), 8 * 8);
+        assert_eq!(count(0.25), 0);
+        // 3 pixel radius around a pixel corner
+        assert_eq!(count(0.75), 32);
+        // upper left pixel of the 4x4 sprite, at (6, 9) from the bottom
+        assert_eq!(color[22 * 32 + 6], glm::vec4(1., 0.125, 0.125, 1.));
+        drop(frame_buf);
+
+        // the stencil masks points and is updated by them
+        rst.set_point_state(PointState { size: 4., round: false });
+        let write = StencilFace { func: CompareFunc::ALWAYS, pass_op: StencilOp::REPLACE, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(write, 1));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH | Buffer::STENCIL);
+        rst.draw(pos_id, square_id, Primitive::POINT);
+        let read = StencilFace { func: CompareFunc::NOT_EQUAL, ..Default::default() };
+        rst.set_stencil_state(StencilState::new(read, 1));
+        rst.set_frame_shader(Box::new(|_: &SFragmentShaderPayload| glm::vec4(1., 1., 1., 1.)));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, square_id, Primitive::POINT);
+        assert!(rst.frame_buf.lock().unwrap().resolved_color().iter().all(|c| c.x == 0.));
+    }
+
+    #[test]
+    fn test_vertex_shader() {
+        let mut rst = Rasterizer::new(32, 32);
+        let pos = [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            ..Default::default()
+        }).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+
+        // squeeze the full screen quad into the left half and color it in the vertex stage
+        rst.set_vertex_shader(Box::new(|payload: &SVertexShaderPayload| {
+            let mut out = default_vs(payload);
+            out.pos.x = out.pos.x * 0.5 - 0.5;
+            for i in 0..3 {
+                out.varyings.set(VARYING_COLOR + i, [1., 0., 0.][i]);
+            }
+            out
+        }));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        for y in 0..32 {
+            for x in 0..32 {
+                // bgra
+                assert_eq!(color[y * 32 + x].z == 1., x < 16, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vertex_cache() {
+        use std::sync::Arc;
+
+        let mut rst = Rasterizer::new(32, 32);
+        let pos = [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            ..Default::default()
+        }).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 0, 2, 3]);
+
+        let invocations = Arc::new(AtomicU32::new(0));
+        let counter = invocations.clone();
+        rst.set_vertex_shader(Box::new(move |payload: &SVertexShaderPayload| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            default_vs(payload)
+        }));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        let instances = vec![SInstance::new(glm::one()); 2];
+        rst.draw_instanced(pos_id, ind_id, Primitive::TRIANGLE, &instances);
+        // 6 indices, 4 unique vertices per instance
+        assert_eq!(invocations.load(Ordering::Relaxed), 2 * 4);
+    }
+
+    #[test]
+    fn test_draw_range() {
+        let mut rst = Rasterizer::new(32, 32);
+        // two sub meshes in one buffer, left half red and right half blue
+        let quad = |x0: f32, color: glm::Vec3| [(x0, -1f32), (x0 + 1., -1.), (x0 + 1., 1.), (x0, 1.)].iter().map(move |&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            color,
+            ..Default::default()
+        }).collect::<Vec<_>>();
+        let mut pos = quad(-1., glm::vec3(1., 0., 0.));
+        pos.extend(quad(0., glm::vec3(0., 0., 1.)));
+        let pos_id = rst.load_position(pos);
+        // both index from 0
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3, 0, 1, 2, 3]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        let lit = |rst: &Rasterizer| {
+            let frame_buf = rst.frame_buf.lock().unwrap();
+            let color = frame_buf.resolved_color();
+            // bgra, (red, blue) pixel count
+            (color.iter().filter(|c| c.z == 1.).count(), color.iter().filter(|c| c.x == 1.).count())
+        };
+
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        // the count runs past the end of the buffer
+        rst.draw_range(pos_id, ind_id, Primitive::TRIANGLE_FAN, 4, 100, 4);
+        assert_eq!(lit(&rst), (0, 16 * 32));
+
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw_arrays(pos_id, Primitive::TRIANGLE_FAN, 0, 4);
+        assert_eq!(lit(&rst), (16 * 32, 0));
+
+        // the last triangle reads outside the buffer with either base vertex
+        // and is skipped, the rest still draws
+        let list_id = rst.load_index_list(vec![4, 5, 6, 4, 6, 7, 0, 1, u32::MAX]);
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw_range(pos_id, list_id, Primitive::TRIANGLE, 0, 9, -4);
+        assert_eq!(lit(&rst), (16 * 32, 0));
+
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw_range(pos_id, list_id, Primitive::TRIANGLE, 0, 9, 0);
+        assert_eq!(lit(&rst), (0, 16 * 32));
+    }
+
+    #[test]
+    fn test_draw_instanced() {
+        let mut rst = Rasterizer::new(32, 32);
+        // 4x4 pixel quad
+        let pos = [(-0.125f32, -0.125f32), (0.125, -0.125), (0.125, 0.125), (-0.125, 0.125)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            ..Default::default()
+        }).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+
+        // red from the instance attributes, green from the instance index
+        rst.set_vertex_shader(Box::new(|payload: &SVertexShaderPayload| {
+            let mut out = default_vs(payload);
+            out.varyings.set(VARYING_COLOR, payload.instance_attributes[0]);
+            out
+        }));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| glm::vec4(payload.color().x, payload.instance_id as f32, 0., 1.)));
+        let instances: Vec<SInstance> = [-0.5f32, 0., 0.5].iter().enumerate().map(|(i, &x)| {
+            SInstance::with_attributes(glm::translation(&glm::vec3(x, 0., 0.)), vec![0.25 * (i + 1) as f32])
+        }).collect();
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw_instanced(pos_id, ind_id, Primitive::TRIANGLE_FAN, &instances);
+
+        let frame_buf = rst.frame_buf.lock().unwrap();
+        let color = frame_buf.resolved_color();
+        assert_eq!(color.iter().filter(|c| c.z != 0.).count(), 3 * 4 * 4);
+        for i in 0..3 {
+            // bgra, centers at x = 8, 16, 24
+            assert_eq!(color[15 * 32 + 8 * (i + 1)], glm::vec4(0., i as f32, 0.25 * (i + 1) as f32, 1.));
+        }
+    }
+
+    #[test]
+    fn test_depth_state() {
+        let mut rst = Rasterizer::new(8, 8);
+        let quad = |z: f32, color: glm::Vec3| [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, z),
+            color,
+            ..Default::default()
+        }).collect::<Vec<_>>();
+        // red at depth 0.5 and blue at 0.75, drawn in that order
+        let near = rst.load_position(quad(0., glm::vec3(1., 0., 0.)));
+        let far = rst.load_position(quad(0.5, glm::vec3(0., 0., 1.)));
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        let (red, blue, clear) = (glm::vec4(0., 0., 1., 1.), glm::vec4(1., 0., 0., 1.), glm::vec4(0., 0., 0., 1.));
+        let winner = |rst: &mut Rasterizer, order: &[PosBufId]| {
+            rst.clear(Buffer::COLOR | Buffer::DEPTH);
+            for &pos_id in order.iter() {
+                rst.draw(pos_id, ind_id, Primitive::TRIANGLE_FAN);
+            }
+            let frame_buf = rst.frame_buf.lock().unwrap();
+            let color = frame_buf.resolved_color();
+            assert!(color.iter().all(|c| *c == color[0]));
+            color[0]
+        };
+
+        let cases = [
+            (CompareFunc::NEVER, clear),
+            (CompareFunc::LESS, red),
+            (CompareFunc::LESS_EQUAL, red),
+            (CompareFunc::EQUAL, clear),
+            (CompareFunc::GREATER, clear),
+            (CompareFunc::GREATER_EQUAL, clear),
+            (CompareFunc::NOT_EQUAL, blue),
+            (CompareFunc::ALWAYS, blue),
+        ];
+        for &(func, expected) in cases.iter() {
+            rst.set_depth_state(DepthState { func, ..Default::default() });
+            assert_eq!(winner(&mut rst, &[near, far]), expected, "{:?}", func);
+        }
+
+        // without depth writes red does not hide blue
+        rst.set_depth_state(DepthState { write: false, ..Default::default() });
+        assert_eq!(winner(&mut rst, &[near, far]), blue);
+        // only blue is behind a clear value of 0.6
+        rst.set_depth_state(DepthState { func: CompareFunc::GREATER, clear_value: 0.6, ..Default::default() });
+        assert_eq!(winner(&mut rst, &[near, far]), blue);
+        rst.set_depth_state(DepthState { func: CompareFunc::EQUAL, clear_value: 0.5, ..Default::default() });
+        assert_eq!(winner(&mut rst, &[near, far]), red);
+
+        // the nearest surface wins in either order with reversed depth
+        rst.set_depth_state(DepthState::default());
+        rst.set_reversed_z(true);
+        assert_eq!(winner(&mut rst, &[near, far]), red);
+        assert_eq!(winner(&mut rst, &[far, near]), red);
+        winner(&mut rst, &[far]);
+        assert_eq!(rst.frame_buf.lock().unwrap().resolved_depth()[0], 0.25);
+    }
+
+    #[test]
+    fn test_reversed_z_precision() {
+        let mut rst = Rasterizer::new(8, 8);
+        let quad = |z: f32, color: glm::Vec3| [(-2000f32, -2000f32), (2000., -2000.), (2000., 2000.), (-2000., 2000.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, z),
+            color,
+            ..Default::default()
+        }).collect::<Vec<_>>();
+        // 5 cm apart, 900 m away from a 10 cm near plane
+        let behind = rst.load_position(quad(900.05, glm::vec3(1., 0., 0.)));
+        let front = rst.load_position(quad(900., glm::vec3(0., 0., 1.)));
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        rst.set_projection(&glm::perspective_fov_lh(std::f32::consts::FRAC_PI_2, 8., 8., 0.1, 1000.));
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        let front_wins = |rst: &Rasterizer| {
+            rst.clear(Buffer::COLOR | Buffer::DEPTH);
+            rst.draw(behind, ind_id, Primitive::TRIANGLE_FAN);
+            rst.draw(front, ind_id, Primitive::TRIANGLE_FAN);
+            // stored as bgr, blue is x
+            rst.frame_buf.lock().unwrap().resolved_color().iter().all(|c| c.x > 0.5)
+        };
+
+        // both round to the same depth near 1
+        assert!(!front_wins(&rst));
+        rst.set_reversed_z(true);
+        assert!(front_wins(&rst));
+    }
+
+    #[test]
+    fn test_early_z() {
+        use std::sync::Arc;
+
+        let quad = |z: f32, color: glm::Vec3| [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, z),
+            color,
+            ..Default::default()
+        }).collect::<Vec<_>>();
+
+        let mut images = Vec::new();
+        for &hierarchical_z in [false, true].iter() {
+            let mut rst = Rasterizer::new_with_msaa(40, 40, 4, SamplePattern::STANDARD).unwrap();
+            rst.set_aa_mode(AntiAliasing::MSAA);
+            rst.set_hierarchical_z(hierarchical_z);
+            let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+            let near_id = rst.load_position(quad(0., glm::vec3(1., 0., 0.)));
+            let far_id = rst.load_position(quad(0.5, glm::vec3(0., 1., 0.)));
+
+            let invocations = Arc::new(AtomicU32::new(0));
+            let counter = invocations.clone();
+            rst.set_frame_shader(Box::new(move |payload: &SFragmentShaderPayload| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                utility::to_vec4(&payload.color(), None)
+            }));
+            rst.clear(Buffer::COLOR | Buffer::DEPTH);
+            rst.draw(near_id, ind_id, Primitive::TRIANGLE_FAN);
+            // pixels on the diagonal are shaded by both triangles
+            assert!(invocations.swap(0, Ordering::Relaxed) >= 40 * 40);
+
+            // fully hidden, never shaded
+            rst.draw(far_id, ind_id, Primitive::TRIANGLE_FAN);
+            assert_eq!(invocations.load(Ordering::Relaxed), 0);
+            images.push(rst.frame_buf.lock().unwrap().resolved_color().to_vec());
+        }
+        assert!(images[0] == images[1]);
+    }
+
+    #[test]
+    fn test_deferred_matches_forward() {
+        use std::sync::Arc;
+
+        let render = |mode: ShadingMode| {
+            // one sample, so every pixel belongs to a single triangle in both modes
+            let mut rst = Rasterizer::new(61, 47);
+            let mut pos = Vec::new();
+            for i in 0..8 {
+                let a = i as f32 * 0.7;
+                let color = glm::vec3(i as f32 / 8., 0.5, 1. - i as f32 / 8.);
+                for &(x, y) in [(a.cos(), a.sin()), (-a.sin(), a.cos()), (0.2 * a, -0.4)].iter() {
+                    pos.push(SVertex { pos: glm::vec3(x, y, 0.1 * i as f32 - 0.4), color, ..Default::default() });
+                }
+            }
+            let ind = (0..pos.len() as u32).collect();
+            let pos_id = rst.load_position(pos);
+            let ind_id = rst.load_index_list(ind);
+
+            let invocations = Arc::new(AtomicU32::new(0));
+            let counter = invocations.clone();
+            rst.set_shading_mode(mode);
+            rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+            rst.set_lighting_shader(Box::new(move |payload: &SLightingShaderPayload| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                payload.texel.albedo
+            }));
+            rst.clear(Buffer::COLOR | Buffer::DEPTH | Buffer::GBUFFER);
+            rst.draw(pos_id, ind_id, Primitive::TRIANGLE);
+            rst.lighting_pass();
+
+            let frame_buf = rst.frame_buf.lock().unwrap();
+            let covered = frame_buf.gbuffer().iter().filter(|t| t.is_some()).count() as u32;
+            // once per visible pixel
+            assert_eq!(invocations.load(Ordering::Relaxed), covered);
+            frame_buf.resolved_color().to_vec()
+        };
+
+        let forward = render(ShadingMode::FORWARD);
+        let deferred = render(ShadingMode::DEFERRED);
+        assert!(forward.iter().any(|c| c.xyz() != glm::Vec3::zeros()));
+        assert!(forward == deferred);
+    }
+
+    #[test]
+    fn test_shadow_map() {
+        use std::sync::Arc;
+
+        let mut rst = Rasterizer::new(8, 8);
+        // one quad in the xz plane, placed as the floor at y = 0 and a
+        // smaller occluder above it by the model matrices
+        let pos_id = rst.load_position([(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, z)| SVertex {
+            pos: glm::vec3(x, 0., z),
+            ..Default::default()
+        }).collect());
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+        let floor = [SInstance::new(glm::scaling(&glm::vec3(4., 1., 4.)))];
+        let occluder = [SInstance::with_attributes(glm::translation(&glm::vec3(0., 1., 0.)), vec![1.])];
+        let draws = [
+            (pos_id, ind_id, Primitive::TRIANGLE_FAN, &floor[..]),
+            (pos_id, ind_id, Primitive::TRIANGLE_FAN, &occluder[..]),
+        ];
+
+        // red full screen quad in the frame buffer of the scene
+        let screen_id = rst.load_position([(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            color: glm::vec3(1., 0., 0.),
+            ..Default::default()
+        }).collect());
+        rst.set_frame_shader(Box::new(|payload: &SFragmentShaderPayload| utility::to_vec4(&payload.color(), None)));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(screen_id, ind_id, Primitive::TRIANGLE_FAN);
+        let before = rst.frame_buf.lock().unwrap().resolved_color().to_vec();
+        assert!(before.iter().all(|c| c.z == 1.));
+
+        let up = glm::vec3(0f32, 1., 0.);
+        let lights = [
+            LightSource::directional(-up, glm::vec3(0., 0.5, 0.), 4.),
+            LightSource::spot(glm::vec3(0., 3., 0.), -up, 2.),
+            LightSource::point(glm::vec3(0., 3., 0.)),
+        ];
+        for light in lights.iter() {
+            let shadow_map = rst.render_shadow_map(light, 64, &draws);
+            // under the occluder, next to it, and the occluder itself
+            assert_eq!(shadow_map.visibility(&glm::vec3(0., 0., 0.), &up), 0., "{:?}", light.kind);
+            assert_eq!(shadow_map.visibility(&glm::vec3(2.5, 0., 0.), &up), 1., "{:?}", light.kind);
+            assert_eq!(shadow_map.visibility(&glm::vec3(0.2, 1., 0.3), &up), 1., "{:?}", light.kind);
+        }
+        // the frame buffer of the scene is untouched
+        assert!(rst.frame_buf.lock().unwrap().resolved_color() == &before[..]);
+
+        // a vertex shader that drops the occluder removes its shadow
+        rst.set_vertex_shader(Box::new(|payload: &SVertexShaderPayload| {
+            let mut out = default_vs(payload);
+            if payload.instance_attributes.first() == Some(&1.) {
+                out.pos = glm::vec4(0., 0., 0., -1.);
+            }
+            out
+        }));
+        let shadow_map = rst.render_shadow_map(&lights[0], 64, &draws);
+        assert_eq!(shadow_map.visibility(&glm::vec3(0., 0., 0.), &up), 1.);
+
+        // the depth pass runs no frame shader and keeps the culled count of
+        // the scene
+        let shaded = Arc::new(AtomicU32::new(0));
+        let counter = shaded.clone();
+        rst.set_frame_shader(Box::new(move |payload: &SFragmentShaderPayload| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            utility::to_vec4(&payload.color(), None)
+        }));
+        rst.render_shadow_map(&lights[2], 64, &draws);
+        assert_eq!(shaded.load(Ordering::Relaxed), 0);
+        rst.set_cull_mode(CullMode::FRONT_AND_BACK);
+        rst.draw(screen_id, ind_id, Primitive::TRIANGLE_FAN);
+        let culled = rst.culled_count();
+        assert!(culled > 0);
+        rst.render_shadow_map(&lights[2], 64, &draws);
+        assert_eq!(rst.culled_count(), culled);
+    }
+
+    #[test]
+    fn test_quad_derivatives() {
+        use std::sync::Arc;
+
+        let mut rst = Rasterizer::new(32, 32);
+        // full screen quad, uv goes 0..1 left to right and 0..2 bottom to top
+        let pos = [(-1f32, -1f32), (1., -1.), (1., 1.), (-1., 1.)].iter().map(|&(x, y)| SVertex {
+            pos: glm::vec3(x, y, 0.),
+            uv: glm::vec3(0.5 * x + 0.5, y + 1., 0.),
+            ..Default::default()
+        }).collect();
+        let pos_id = rst.load_position(pos);
+        let ind_id = rst.load_index_list(vec![0, 1, 2, 3]);
+
+        let derivatives = Arc::new(Mutex::new(Vec::new()));
+        let out = derivatives.clone();
+        rst.set_frame_shader(Box::new(move |payload: &SFragmentShaderPayload| {
+            out.lock().unwrap().push((payload.ddx.vec2(VARYING_TEX_COORDS), payload.ddy.vec2(VARYING_TEX_COORDS)));
+            glm::vec4(1., 1., 1., 1.)
+        }));
+        rst.clear(Buffer::COLOR | Buffer::DEPTH);
+        rst.draw(pos_id, ind_id, Primitive::TRIANGLE_FAN);
+
+        let derivatives = derivatives.lock().unwrap();
+        assert_eq!(derivatives.len(), 32 * 32);
+        for (ddx, ddy) in derivatives.iter() {
+            // also on the diagonal, where quads stick out of the triangle
+            assert!((ddx - glm::vec2(1. / 32., 0.)).norm() < 1e-5, "{:?}", ddx);
+            assert!((ddy - glm::vec2(0., 2. / 32.)).norm() < 1e-5, "{:?}", ddy);
+        }
+    }
+}
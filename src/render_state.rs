@@ -0,0 +1,521 @@
+#![allow(dead_code)]
+
+// Fixed function pipeline state set on the `Rasterizer`.
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    NONE,
+    BACK,
+    FRONT,
+    FRONT_AND_BACK,
+}
+
+/// Winding of front facing triangles on screen (y up).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontFace {
+    CW,
+    CCW,
+}
+
+impl CullMode {
+    pub fn culls(&self, front_facing: bool) -> bool {
+        match self {
+            CullMode::NONE => false,
+            CullMode::BACK => !front_facing,
+            CullMode::FRONT => front_facing,
+            CullMode::FRONT_AND_BACK => true,
+        }
+    }
+}
+
+impl FrontFace {
+    // `signed_area` > 0 means counter clockwise
+    pub fn is_front(&self, signed_area: f32) -> bool {
+        match self {
+            FrontFace::CCW => signed_area > 0.,
+            FrontFace::CW => signed_area < 0.,
+        }
+    }
+}
+
+/// Passes when `value <func> stored`.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareFunc {
+    NEVER,
+    LESS,
+    LESS_EQUAL,
+    EQUAL,
+    GREATER,
+    GREATER_EQUAL,
+    NOT_EQUAL,
+    ALWAYS,
+}
+
+impl CompareFunc {
+    pub fn test<T: PartialOrd>(&self, value: T, stored: T) -> bool {
+        match self {
+            CompareFunc::NEVER => false,
+            CompareFunc::LESS => value < stored,
+            CompareFunc::LESS_EQUAL => value <= stored,
+            CompareFunc::EQUAL => value == stored,
+            CompareFunc::GREATER => value > stored,
+            CompareFunc::GREATER_EQUAL => value >= stored,
+            CompareFunc::NOT_EQUAL => value != stored,
+            CompareFunc::ALWAYS => true,
+        }
+    }
+
+    /// Whether some value in [min, max] may pass against some stored value
+    /// in [stored_min, stored_max].
+    pub fn may_pass(&self, min: f32, max: f32, stored_min: f32, stored_max: f32) -> bool {
+        match self {
+            CompareFunc::NEVER => false,
+            CompareFunc::LESS => min < stored_max,
+            CompareFunc::LESS_EQUAL => min <= stored_max,
+            CompareFunc::EQUAL => min <= stored_max && max >= stored_min,
+            CompareFunc::GREATER => max > stored_min,
+            CompareFunc::GREATER_EQUAL => max >= stored_min,
+            CompareFunc::NOT_EQUAL => !(min == max && stored_min == stored_max && min == stored_min),
+            CompareFunc::ALWAYS => true,
+        }
+    }
+
+    /// The same test in the other direction, LESS becomes GREATER.
+    pub fn reversed(&self) -> CompareFunc {
+        match self {
+            CompareFunc::LESS => CompareFunc::GREATER,
+            CompareFunc::LESS_EQUAL => CompareFunc::GREATER_EQUAL,
+            CompareFunc::GREATER => CompareFunc::LESS,
+            CompareFunc::GREATER_EQUAL => CompareFunc::LESS_EQUAL,
+            func => *func,
+        }
+    }
+}
+
+/// Depth values are window space, 0 at the near plane and 1 at the far plane,
+/// or the other way around with `reversed_z`. Flip `reversed_z` with
+/// `with_reversed_z`, the compare function and clear value depend on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthState {
+    pub func: CompareFunc,
+    pub write: bool,
+    pub clear_value: f32,
+    pub reversed_z: bool,
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        DepthState {
+            func: CompareFunc::LESS,
+            write: true,
+            clear_value: 1.0,
+            reversed_z: false,
+        }
+    }
+}
+
+impl DepthState {
+    /// Far plane at 0, near plane at 1, compared with GREATER and cleared
+    /// to 0. The reversal is applied to the projection before the divide,
+    /// floats are denser near 0 which balances the 1/z distribution of
+    /// perspective depth.
+    pub fn reversed_z() -> DepthState {
+        DepthState::default().with_reversed_z(true)
+    }
+
+    /// Turn reversed depth on or off. The compare function and the clear
+    /// value are flipped with it, so the nearest surface still wins.
+    pub fn with_reversed_z(self, reversed_z: bool) -> DepthState {
+        if reversed_z == self.reversed_z {
+            return self;
+        }
+        DepthState {
+            func: self.func.reversed(),
+            clear_value: 1. - self.clear_value,
+            reversed_z,
+            ..self
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendFactor {
+    ZERO,
+    ONE,
+    SRC_COLOR,
+    ONE_MINUS_SRC_COLOR,
+    DST_COLOR,
+    ONE_MINUS_DST_COLOR,
+    SRC_ALPHA,
+    ONE_MINUS_SRC_ALPHA,
+    DST_ALPHA,
+    ONE_MINUS_DST_ALPHA,
+    CONSTANT_COLOR,
+    ONE_MINUS_CONSTANT_COLOR,
+    SRC_ALPHA_SATURATE,
+}
+
+/// MIN and MAX ignore the factors.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOp {
+    ADD,
+    SUBTRACT,
+    REVERSE_SUBTRACT,
+    MIN,
+    MAX,
+}
+
+/// `src` is the frame shader output, `dst` the color already in the buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlendState {
+    pub enable: bool,
+    pub src_color: BlendFactor,
+    pub dst_color: BlendFactor,
+    pub color_op: BlendOp,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor,
+    pub alpha_op: BlendOp,
+    pub constant: glm::Vec4,
+}
+
+impl Default for BlendState {
+    fn default() -> Self {
+        BlendState {
+            enable: false,
+            src_color: BlendFactor::ONE,
+            dst_color: BlendFactor::ZERO,
+            color_op: BlendOp::ADD,
+            src_alpha: BlendFactor::ONE,
+            dst_alpha: BlendFactor::ZERO,
+            alpha_op: BlendOp::ADD,
+            constant: glm::vec4(0., 0., 0., 0.),
+        }
+    }
+}
+
+impl BlendFactor {
+    fn value(&self, src: &glm::Vec4, dst: &glm::Vec4, constant: &glm::Vec4) -> glm::Vec4 {
+        let one = glm::vec4(1f32, 1., 1., 1.);
+        match self {
+            BlendFactor::ZERO => glm::Vec4::zeros(),
+            BlendFactor::ONE => one,
+            BlendFactor::SRC_COLOR => *src,
+            BlendFactor::ONE_MINUS_SRC_COLOR => one - src,
+            BlendFactor::DST_COLOR => *dst,
+            BlendFactor::ONE_MINUS_DST_COLOR => one - dst,
+            BlendFactor::SRC_ALPHA => one * src.w,
+            BlendFactor::ONE_MINUS_SRC_ALPHA => one * (1. - src.w),
+            BlendFactor::DST_ALPHA => one * dst.w,
+            BlendFactor::ONE_MINUS_DST_ALPHA => one * (1. - dst.w),
+            BlendFactor::CONSTANT_COLOR => *constant,
+            BlendFactor::ONE_MINUS_CONSTANT_COLOR => one - constant,
+            BlendFactor::SRC_ALPHA_SATURATE => {
+                let f = f32::min(src.w, 1. - dst.w);
+                glm::vec4(f, f, f, 1.)
+            }
+        }
+    }
+}
+
+impl BlendOp {
+    fn apply(&self, src: f32, src_factor: f32, dst: f32, dst_factor: f32) -> f32 {
+        match self {
+            BlendOp::ADD => src * src_factor + dst * dst_factor,
+            BlendOp::SUBTRACT => src * src_factor - dst * dst_factor,
+            BlendOp::REVERSE_SUBTRACT => dst * dst_factor - src * src_factor,
+            BlendOp::MIN => f32::min(src, dst),
+            BlendOp::MAX => f32::max(src, dst),
+        }
+    }
+}
+
+impl BlendState {
+    /// Classic "over" compositing for non premultiplied colors.
+    pub fn alpha_blending() -> BlendState {
+        BlendState {
+            enable: true,
+            src_color: BlendFactor::SRC_ALPHA,
+            dst_color: BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_op: BlendOp::ADD,
+            src_alpha: BlendFactor::ONE,
+            dst_alpha: BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_op: BlendOp::ADD,
+            ..Default::default()
+        }
+    }
+
+    /// Both colors in the same channel order (rgba).
+    pub fn blend(&self, src: &glm::Vec4, dst: &glm::Vec4) -> glm::Vec4 {
+        if !self.enable {
+            return *src;
+        }
+        let src_color = self.src_color.value(src, dst, &self.constant);
+        let dst_color = self.dst_color.value(src, dst, &self.constant);
+        let src_alpha = self.src_alpha.value(src, dst, &self.constant);
+        let dst_alpha = self.dst_alpha.value(src, dst, &self.constant);
+
+        let mut out = glm::Vec4::zeros();
+        for i in 0..3 {
+            out[i] = self.color_op.apply(src[i], src_color[i], dst[i], dst_color[i]);
+        }
+        out.w = self.alpha_op.apply(src.w, src_alpha.w, dst.w, dst_alpha.w);
+        out
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StencilOp {
+    KEEP,
+    ZERO,
+    REPLACE,
+    /// saturates at 255
+    INCR,
+    INCR_WRAP,
+    /// saturates at 0
+    DECR,
+    DECR_WRAP,
+    INVERT,
+}
+
+impl StencilOp {
+    pub fn apply(&self, stored: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::KEEP => stored,
+            StencilOp::ZERO => 0,
+            StencilOp::REPLACE => reference,
+            StencilOp::INCR => stored.saturating_add(1),
+            StencilOp::INCR_WRAP => stored.wrapping_add(1),
+            StencilOp::DECR => stored.saturating_sub(1),
+            StencilOp::DECR_WRAP => stored.wrapping_sub(1),
+            StencilOp::INVERT => !stored,
+        }
+    }
+}
+
+/// Stencil function and operations of one triangle facing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilFace {
+    pub func: CompareFunc,
+    pub fail_op: StencilOp,
+    pub depth_fail_op: StencilOp,
+    pub pass_op: StencilOp,
+}
+
+impl Default for StencilFace {
+    fn default() -> Self {
+        StencilFace {
+            func: CompareFunc::ALWAYS,
+            fail_op: StencilOp::KEEP,
+            depth_fail_op: StencilOp::KEEP,
+            pass_op: StencilOp::KEEP,
+        }
+    }
+}
+
+/// Test is `(reference & read_mask) <func> (stored & read_mask)`,
+/// only the `write_mask` bits of the stored value get updated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilState {
+    pub enable: bool,
+    pub front: StencilFace,
+    pub back: StencilFace,
+    pub reference: u8,
+    pub read_mask: u8,
+    pub write_mask: u8,
+    pub clear_value: u8,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        StencilState {
+            enable: false,
+            front: StencilFace::default(),
+            back: StencilFace::default(),
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            clear_value: 0,
+        }
+    }
+}
+
+impl StencilState {
+    /// Same function and operations for both facings.
+    pub fn new(face: StencilFace, reference: u8) -> StencilState {
+        StencilState {
+            enable: true,
+            front: face,
+            back: face,
+            reference,
+            ..Default::default()
+        }
+    }
+
+    pub fn face(&self, front_facing: bool) -> &StencilFace {
+        if front_facing { &self.front } else { &self.back }
+    }
+
+    pub fn test(&self, face: &StencilFace, stored: u8) -> bool {
+        face.func.test(self.reference & self.read_mask, stored & self.read_mask)
+    }
+
+    pub fn update(&self, op: StencilOp, stored: u8) -> u8 {
+        (stored & !self.write_mask) | (op.apply(stored, self.reference) & self.write_mask)
+    }
+}
+
+/// Pixel rectangle, (x, y) is the bottom left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.x + self.width as i32 && y >= self.y && y < self.y + self.height as i32
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x0 = i32::max(self.x, other.x);
+        let y0 = i32::max(self.y, other.y);
+        let x1 = i32::min(self.x + self.width as i32, other.x + other.width as i32);
+        let y1 = i32::min(self.y + self.height as i32, other.y + other.height as i32);
+        Rect::new(x0, y0, i32::max(x1 - x0, 0) as u32, i32::max(y1 - y0, 0) as u32)
+    }
+}
+
+/// LINE and LINE_STRIP primitives, also the edges of the LINE and
+/// FILL_WIREFRAME polygon modes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineState {
+    /// in pixels, aliased lines measure it along the minor axis
+    pub width: f32,
+    /// coverage is multiplied into the alpha and blended, with alpha
+    /// blending if the blend state is off
+    pub antialiased: bool,
+}
+
+impl Default for LineState {
+    fn default() -> Self {
+        LineState {
+            width: 1.,
+            antialiased: false,
+        }
+    }
+}
+
+/// POINT primitives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointState {
+    /// diameter in pixels, used when the vertex shader sets no point size
+    pub size: f32,
+    /// round splats instead of squares
+    pub round: bool,
+}
+
+impl Default for PointState {
+    fn default() -> Self {
+        PointState {
+            size: 1.,
+            round: false,
+        }
+    }
+}
+
+/// How triangles are drawn.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolygonMode {
+    FILL,
+    /// edges only
+    LINE,
+    /// vertices only
+    POINT,
+    /// filled, with the edges drawn on top
+    FILL_WIREFRAME,
+}
+
+impl Default for PolygonMode {
+    fn default() -> Self {
+        PolygonMode::FILL
+    }
+}
+
+/// Look of the edges and vertices of LINE, POINT and FILL_WIREFRAME.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WireframeState {
+    /// rgba, goes through the blend state
+    pub color: glm::Vec4,
+    /// line width and point diameter in pixels
+    pub width: f32,
+}
+
+impl Default for WireframeState {
+    fn default() -> Self {
+        WireframeState {
+            color: glm::vec4(1., 1., 1., 1.),
+            width: 1.,
+        }
+    }
+}
+
+/// NDC to window transform.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub min_depth: f32,
+    pub max_depth: f32,
+}
+
+impl Viewport {
+    pub fn new(x: f32, y: f32, width: f32, height: f32, min_depth: f32, max_depth: f32) -> Viewport {
+        Viewport { x, y, width, height, min_depth, max_depth }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpha_blending() {
+        let blend = BlendState::alpha_blending();
+        let src = glm::vec4(1f32, 0., 0., 0.25);
+        let dst = glm::vec4(0f32, 0., 1., 1.);
+        assert_eq!(blend.blend(&src, &dst), glm::vec4(0.25, 0., 0.75, 1.));
+
+        // disabled blending replaces
+        assert_eq!(BlendState::default().blend(&src, &dst), src);
+
+        let max = BlendState { enable: true, color_op: BlendOp::MAX, alpha_op: BlendOp::MAX, ..Default::default() };
+        assert_eq!(max.blend(&src, &dst), glm::vec4(1., 0., 1., 1.));
+    }
+
+    #[test]
+    fn test_reversed_z_state() {
+        let reversed = DepthState::reversed_z();
+        assert_eq!((reversed.func, reversed.clear_value, reversed.reversed_z), (CompareFunc::GREATER, 0., true));
+        assert_eq!(reversed.with_reversed_z(true), reversed);
+        assert_eq!(reversed.with_reversed_z(false), DepthState::default());
+
+        let state = DepthState { func: CompareFunc::LESS_EQUAL, write: false, ..Default::default() };
+        let flipped = state.with_reversed_z(true);
+        assert_eq!((flipped.func, flipped.write), (CompareFunc::GREATER_EQUAL, false));
+        assert_eq!(CompareFunc::NOT_EQUAL.reversed(), CompareFunc::NOT_EQUAL);
+    }
+}
@@ -1,19 +1,113 @@
+use crate::rasterizer::SVertex;
+use crate::varying::Varyings;
+use crate::uniform::Uniforms;
+use crate::gbuffer::GBufferTexel;
 
-// 暂时不用
-pub struct SVertexShaderPayload {
-    pub position: glm::Vec3,
+// varying layout written by `default_vs` and read by the built in frame shaders
+pub const VARYING_POSITION: usize = 0;
+pub const VARYING_NORMAL: usize = 3;
+pub const VARYING_TEX_COORDS: usize = 6;
+pub const VARYING_COLOR: usize = 8;
+
+// per draw transforms of the vertex shader
+pub struct SVertexTransforms {
+    pub model: glm::Mat4,
+    pub view: glm::Mat4,
+    pub projection: glm::Mat4,
+    // projection * view * model
+    pub pvm: glm::Mat4,
+    // inverse transpose of model
+    pub normal_matrix: glm::Mat4,
+}
+
+// one copy of the mesh in `Rasterizer::draw_instanced`
+#[derive(Debug, Clone)]
+pub struct SInstance {
+    pub model: glm::Mat4,
+    // free form per instance data for the vertex shader
+    pub attributes: Vec<f32>,
 }
 
+impl SInstance {
+    pub fn new(model: glm::Mat4) -> SInstance {
+        SInstance { model, attributes: Vec::new() }
+    }
+
+    pub fn with_attributes(model: glm::Mat4, attributes: Vec<f32>) -> SInstance {
+        SInstance { model, attributes }
+    }
+}
+
+pub struct SVertexShaderPayload<'a> {
+    pub vertex: &'a SVertex,
+    // index into the instances of the draw, 0 for `draw`
+    pub instance_id: u32,
+    pub instance_attributes: &'a [f32],
+    pub transforms: &'a SVertexTransforms,
+    pub uniforms: &'a Uniforms,
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct SVertexShaderOutPayload{
-    pub position: glm::Vec3,
+    // clip space position
+    pub pos: glm::Vec4,
+    // interpolated for the frame shader
+    pub varyings: Varyings,
+    // diameter of POINT primitives in pixels, None takes the point state size
+    pub point_size: Option<f32>,
 }
 
-pub struct SFragmentShaderPayload{
-    pub eye_pos: glm::Vec3,
-    pub position: glm::Vec3,
-    pub color: glm::Vec3,
-    pub normal: glm::Vec3,
-    pub tex_coords: glm::Vec2,
+pub struct SFragmentShaderPayload<'a>{
+    pub varyings: Varyings,
+    // change of the varyings one pixel to the right and one pixel up,
+    // shared by the 2x2 quad of the fragment. extrapolated from the
+    // barycentrics of the primitive, the quad is not shaded together and
+    // there are no helper invocations
+    pub ddx: &'a Varyings,
+    pub ddy: &'a Varyings,
+    // position inside a point sprite, (0, 0) is the upper left corner.
+    // zero for lines and triangles
+    pub point_coord: glm::Vec2,
+    pub instance_id: u32,
+    pub uniforms: &'a Uniforms,
+}
+
+impl<'a> SFragmentShaderPayload<'a> {
+    pub fn dfdx(&self, offset: usize) -> f32 {
+        self.ddx.get(offset)
+    }
+
+    pub fn dfdy(&self, offset: usize) -> f32 {
+        self.ddy.get(offset)
+    }
+
+    // attributes of the default varying layout
+    pub fn position(&self) -> glm::Vec3 {
+        self.varyings.vec3(VARYING_POSITION)
+    }
+
+    pub fn normal(&self) -> glm::Vec3 {
+        self.varyings.vec3(VARYING_NORMAL)
+    }
+
+    pub fn tex_coords(&self) -> glm::Vec2 {
+        self.varyings.vec2(VARYING_TEX_COORDS)
+    }
+
+    pub fn color(&self) -> glm::Vec3 {
+        self.varyings.vec3(VARYING_COLOR)
+    }
+
+    /// Light visibility from the shadow map in `slot`, 1 when none is bound.
+    pub fn shadow(&self, slot: usize) -> f32 {
+        self.uniforms.shadow_map(slot).map_or(1., |m| m.visibility(&self.position(), &self.normal().normalize()))
+    }
+}
 
-    pub texture: opencv::prelude::Mat,
+// input of the deferred lighting pass, one per covered pixel
+pub struct SLightingShaderPayload<'a>{
+    // pixel center in screen space
+    pub screen_pos: glm::Vec2,
+    pub texel: &'a GBufferTexel,
+    pub uniforms: &'a Uniforms,
 }
@@ -1,21 +1,48 @@
-use crate::shader::{SFragmentShaderPayload, SVertexShaderPayload};
+use crate::shader::*;
 use std::default::Default;
 use std::boxed::Box;
-use opencv::core::MatTrait;
-use opencv::prelude::MatTraitManual;
-use crate::shader_utility::{texture_sample, texture_sample2};
-
-pub type VertexShaderProgram=Box<dyn Fn(&SVertexShaderPayload) -> glm::Vec3>;
-pub type FrameShaderProgram=Box<dyn Fn(&SFragmentShaderPayload) -> glm::Vec3>;
+use crate::shader_utility::{texture_sample, texture_sample_grad};
+use crate::utility::to_vec4;
+use crate::varying::{Interpolation, Varyings};
+use crate::gbuffer::GBufferTexel;
+use crate::uniform::Uniforms;
+
+// returns the clip space position and the attributes for the frame shader
+pub type VertexShaderProgram=Box<dyn Fn(&SVertexShaderPayload) -> SVertexShaderOutPayload + Send + Sync>;
+// returns rgba
+pub type FrameShaderProgram=Box<dyn Fn(&SFragmentShaderPayload) -> glm::Vec4 + Send + Sync>;
+// geometry pass of deferred shading, returns the surface of the fragment
+pub type GBufferShaderProgram=Box<dyn Fn(&SFragmentShaderPayload) -> GBufferTexel + Send + Sync>;
+// lighting pass of deferred shading, returns rgba
+pub type LightingShaderProgram=Box<dyn Fn(&SLightingShaderPayload) -> glm::Vec4 + Send + Sync>;
+
+// vertex shader
+pub fn default_vs(vs_payload: &SVertexShaderPayload) -> SVertexShaderOutPayload {
+    let vert = vs_payload.vertex;
+    let transforms = vs_payload.transforms;
+    let v4_pos = to_vec4(&vert.pos, None);
+    let v4_normal = to_vec4(&vert.normal, None);
+    // default varying layout, see VARYING_POSITION
+    let mut varyings = Varyings::new();
+    varyings.push_vec3(Interpolation::SMOOTH, &(transforms.model * v4_pos).xyz());
+    varyings.push_vec3(Interpolation::SMOOTH, &(transforms.normal_matrix * v4_normal).xyz().normalize());
+    varyings.push_vec2(Interpolation::SMOOTH, &vert.uv.xy());
+    varyings.push_vec3(Interpolation::SMOOTH, &vert.color);
+    SVertexShaderOutPayload {
+        pos: transforms.pvm * v4_pos,
+        varyings,
+        point_size: None,
+    }
+}
 
 // frame shader
-pub fn empty_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
-    return glm::vec3(1.0f32, 1.0, 1.0);
+pub fn empty_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec4 {
+    return glm::vec4(1.0f32, 1.0, 1.0, 1.0);
 }
 
-pub fn normal_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3{
-    let mut result_color = (fs_payload.normal.normalize() + glm::vec3(1., 1., 1.)).scale(0.5);
-    return result_color;
+pub fn normal_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec4{
+    let mut result_color = (fs_payload.normal().normalize() + glm::vec3(1., 1., 1.)).scale(0.5);
+    return to_vec4(&result_color, None);
 }
 
 struct Light {
@@ -23,72 +50,102 @@ struct Light {
     pub I: glm::Vec3,
 }
 
-pub fn phone_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
+pub fn phone_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec4 {
     let tex_color;
-    if !fs_payload.texture.empty().unwrap() {
-        tex_color = texture_sample(&fs_payload.texture, &fs_payload.tex_coords).xyz();
-        // println!("tex_color: {:?}, {:?}", tex_color, fs_payload.tex_coords);
+    if let Some(texture) = fs_payload.uniforms.texture(0) {
+        tex_color = texture_sample(texture, &fs_payload.tex_coords()).xyz();
+        // println!("tex_color: {:?}, {:?}", tex_color, fs_payload.tex_coords());
     }
     else {
-        tex_color = fs_payload.color;
+        tex_color = fs_payload.color();
     }
 
     let ka = glm::vec3(0.005f32, 0.005, 0.005);
-    let kd = fs_payload.color;
+    let kd = fs_payload.color();
     let ks = glm::vec3(0.7939, 0.7937, 0.7937);
 
-    let l1 = Light {
-        pos: glm::vec3(20., 20., 20.),
-        I: glm::vec3(1.0, 1.0, 1.0),
-    };
-    let l2 = Light {
-        pos: glm::vec3(-20., 20., 0.),
+    let eye_pos = fs_payload.uniforms.get::<glm::Vec3>("eye_pos").unwrap_or_default();
+    let out_color = phong(&tex_color, &fs_payload.position(), &fs_payload.normal().normalize(), &eye_pos, fs_payload.uniforms);
+    return to_vec4(&out_color, None);
+}
+
+// positions of the two phong lights
+pub const PHONG_LIGHTS: [[f32; 3]; 2] = [[20., 20., 20.], [-20., 20., 0.]];
+// int uniforms with the shadow map slot of each phong light, a light is
+// unshadowed while its uniform is unset or names an empty slot
+pub const PHONG_SHADOW_SLOTS: [&str; 2] = ["light0_shadow_slot", "light1_shadow_slot"];
+
+// blinn-phong with two fixed lights
+fn phong(color: &glm::Vec3, view_pos: &glm::Vec3, normal: &glm::Vec3, eye_pos: &glm::Vec3, uniforms: &Uniforms) -> glm::Vec3 {
+    let lights = PHONG_LIGHTS.iter().map(|pos| Light {
+        pos: glm::make_vec3(pos),
         I: glm::vec3(1.0, 1.0, 1.0),
-    };
-    let lights = [l1, l2];
+    });
     let amb_light_I = glm::vec3(0.04, 0.04, 0.04);
-    let eye_pos = fs_payload.eye_pos;
-    let view_pos = fs_payload.position;
 
     let p = 150.0f32;
 
-    let color = tex_color;
-    let normal = fs_payload.normal.normalize();
-
     let mut out_color = glm::zero();
-    for l in lights.iter() {
+    for (i, l) in lights.enumerate() {
+        let visibility = uniforms.get::<i32>(PHONG_SHADOW_SLOTS[i])
+            .and_then(|slot| uniforms.shadow_map(slot as usize))
+            .map_or(1., |m| m.visibility(view_pos, normal));
+
         let ol = (l.pos - view_pos).normalize();
         let oe = (eye_pos - view_pos).normalize();
         let half_mid = (ol + oe).normalize();
-        let nl_ct = f32::max(glm::dot(&normal, &ol), 0.0f32);
-        let nh_ct = f32::max(glm::dot(&normal, &half_mid), 0.0f32);
+        let nl_ct = f32::max(glm::dot(normal, &ol), 0.0f32);
+        let nh_ct = f32::max(glm::dot(normal, &half_mid), 0.0f32);
 
-        let ambient_color = glm::matrix_comp_mult(&color, &amb_light_I);
-        let diffuse_color = glm::matrix_comp_mult(&color, &(nl_ct * l.I));
+        let ambient_color = glm::matrix_comp_mult(color, &amb_light_I);
+        let diffuse_color = glm::matrix_comp_mult(color, &(nl_ct * l.I));
         let specular_color = l.I * nh_ct.powf(p);
 
         out_color += ambient_color;
-        out_color += diffuse_color;
-        out_color += specular_color;
+        out_color += diffuse_color * visibility;
+        out_color += specular_color * visibility;
     }
 
-    return out_color;
+    out_color
 }
 
-pub fn texture_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
+// deferred shading
+pub fn gbuffer_fs(fs_payload: &SFragmentShaderPayload) -> GBufferTexel {
+    let albedo = match fs_payload.uniforms.texture(0) {
+        Some(texture) => texture_sample(texture, &fs_payload.tex_coords()),
+        None => to_vec4(&fs_payload.color(), None),
+    };
+    GBufferTexel {
+        position: fs_payload.position(),
+        normal: fs_payload.normal().normalize(),
+        albedo,
+        uv: fs_payload.tex_coords(),
+        material_id: fs_payload.uniforms.get::<i32>("material_id").unwrap_or(0) as u32,
+    }
+}
+
+// phone_fs on the G-buffer
+pub fn phone_ls(ls_payload: &SLightingShaderPayload) -> glm::Vec4 {
+    let texel = ls_payload.texel;
+    let eye_pos = ls_payload.uniforms.get::<glm::Vec3>("eye_pos").unwrap_or_default();
+    let out_color = phong(&texel.albedo.xyz(), &texel.position, &texel.normal, &eye_pos, ls_payload.uniforms);
+    return to_vec4(&out_color, Some(texel.albedo.w));
+}
+
+pub fn texture_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec4 {
     let tex_color;
-    if !fs_payload.texture.empty().unwrap() {
-        tex_color = texture_sample(&fs_payload.texture, &fs_payload.tex_coords).xyz();
-        // println!("tex_color: {:?}, {:?}", tex_color, fs_payload.tex_coords);
+    if let Some(texture) = fs_payload.uniforms.texture(0) {
+        tex_color = texture_sample(texture, &fs_payload.tex_coords()).xyz();
+        // println!("tex_color: {:?}, {:?}", tex_color, fs_payload.tex_coords());
     }
     else {
-        tex_color = fs_payload.color;
+        tex_color = fs_payload.color();
     }
-    return tex_color;
+    return to_vec4(&tex_color, None);
 }
 
-pub fn bump_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
-    let n = fs_payload.normal.normalize();
+pub fn bump_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec4 {
+    let n = fs_payload.normal().normalize();
     let _v = glm::vec1(n.x * n.x + n.z * n.z);
     let _sqrt = glm::sqrt(&_v).x;
     let t = glm::vec3(
@@ -107,14 +164,18 @@ pub fn bump_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
     let kh = 0.2;
     let kn = 0.1;
     let kk = 255.0;
-    let w = fs_payload.texture.size().unwrap().width as f32;
-    let h = fs_payload.texture.size().unwrap().height as f32;
-    let uv = fs_payload.tex_coords;
-    let uv1 = glm::vec2(uv.x + 1f32/w, uv.y);
-    let uv2 = glm::vec2(uv.x, uv.y + 1f32/h);
-    let uv_c = texture_sample2(&fs_payload.texture, &uv).norm();
-    let dU = kh * kn * (texture_sample2(&fs_payload.texture, &uv1).norm() - uv_c);
-    let dV = kh * kn * (texture_sample2(&fs_payload.texture, &uv2).norm() - uv_c);
+    let texture = match fs_payload.uniforms.texture(0) {
+        Some(texture) => texture,
+        None => return to_vec4(&n, None),
+    };
+    // height change to the neighbour pixels of the quad
+    let uv = fs_payload.tex_coords();
+    let ddx = fs_payload.ddx.vec2(VARYING_TEX_COORDS);
+    let ddy = fs_payload.ddy.vec2(VARYING_TEX_COORDS);
+    let height = |uv: &glm::Vec2| texture_sample_grad(texture, uv, &ddx, &ddy).norm();
+    let uv_c = height(&uv);
+    let dU = kh * kn * (height(&(uv + ddx)) - uv_c);
+    let dV = kh * kn * (height(&(uv + ddy)) - uv_c);
 
     let ln = glm::vec3(-dU, -dV, 1f32);
 
@@ -123,5 +184,34 @@ pub fn bump_fs(fs_payload: &SFragmentShaderPayload) -> glm::Vec3 {
 
     let w_normal = (tbn * ln).normalize();
     // let c_normal = (w_normal + glm::vec3(1., 1., 1.)).scale(0.5);
-    return w_normal;
+    return to_vec4(&w_normal, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shadow::{LightSource, ShadowFace, ShadowMap};
+
+    #[test]
+    fn test_phong_shadow_slots() {
+        // one texel at depth 0, everything behind it is in shadow
+        let face = ShadowFace { view_projection: glm::one(), size: 1, depth: vec![0.] };
+        let light = LightSource::directional(glm::vec3(0., 0., 1.), glm::vec3(0., 0., 0.), 1.);
+        let mut uniforms = Uniforms::new();
+        uniforms.bind_shadow_map(2, ShadowMap::new(light, vec![face]));
+
+        let shade = |uniforms: &Uniforms| {
+            let (color, pos, normal) = (glm::vec3(1., 1., 1.), glm::vec3(0., 0., 0.), glm::vec3(0., 0.6, 0.8));
+            phong(&color, &pos, &normal, &glm::vec3(0., 0., 10.), uniforms)
+        };
+        // bound but not assigned to a light
+        let lit = shade(&uniforms);
+        uniforms.set(PHONG_SHADOW_SLOTS[1], 2i32);
+        let one = shade(&uniforms);
+        uniforms.set(PHONG_SHADOW_SLOTS[0], 2i32);
+        let both = shade(&uniforms);
+        assert!(both.x < one.x && one.x < lit.x);
+        // only the ambient terms are left
+        assert!((both.x - 0.08).abs() < 1e-6);
+    }
 }
@@ -1,17 +1,16 @@
-use opencv::prelude::MatTraitManual;
-use opencv::core::{MatTrait, UMatUsageFlags, Vec3b};
-use opencv::core::AccessFlag::ACCESS_READ;
 use std::f32::consts::PI;
 
+use crate::uniform::Texture;
+
 pub fn reflect(vec: &glm::Vec3, axis: &glm::Vec3) -> glm::Vec3{
     let cos_theta = vec.dot(axis);
     let r_vec = 2. * cos_theta * axis - vec;
     return r_vec.normalize();
 }
 
-pub fn texture_sample(texture: &opencv::prelude::Mat, uv: &glm::Vec2) -> glm::Vec4{
-    let width = texture.rows();
-    let height = texture.cols();
+pub fn texture_sample(texture: &Texture, uv: &glm::Vec2) -> glm::Vec4{
+    let width = texture.size().1 as i32;
+    let height = texture.size().0 as i32;
     let _mat = glm::Mat4x4::identity();
     let _mat = glm::rotate(&_mat, PI / 2., &glm::vec3(0., 0., 1.));
     let _uv = _mat * glm::vec4(uv.x, uv.y, 0., 0.);
@@ -20,18 +19,22 @@ pub fn texture_sample(texture: &opencv::prelude::Mat, uv: &glm::Vec2) -> glm::Ve
     let u = (((uv.x % 1. + 1.) * width  as f32) as i32) % width;
     let v = (((uv.y % 1. + 1.) * height as f32) as i32) % height;
     // let u = width - u;
-    let pixel = *texture.at_2d::<Vec3b>(u, v).unwrap();
-    return glm::vec4(
-        pixel[2] as f32 / 255.0,
-        pixel[1] as f32 / 255.0,
-        pixel[0] as f32 / 255.0,
-        1.0f32,
-    );
+    return texture.texel(u, v);
+}
+
+// mip level of a lookup, from the screen space derivatives of the uv.
+// u runs along the rows like in texture_sample2
+pub fn texture_lod(texture: &Texture, ddx_uv: &glm::Vec2, ddy_uv: &glm::Vec2) -> f32 {
+    let size = glm::vec2(texture.size().1 as f32, texture.size().0 as f32);
+    let dx = ddx_uv.component_mul(&size);
+    let dy = ddy_uv.component_mul(&size);
+    let rho2 = f32::max(dx.norm_squared(), dy.norm_squared());
+    f32::max(0.5 * rho2.log2(), 0.)
 }
 
-pub fn texture_sample2(texture: &opencv::prelude::Mat, uv: &glm::Vec2) -> glm::Vec3{
-    let width = texture.rows();
-    let height = texture.cols();
+pub fn texture_sample2(texture: &Texture, uv: &glm::Vec2) -> glm::Vec3{
+    let width = texture.size().1 as i32;
+    let height = texture.size().0 as i32;
 
     let mut u = (uv.x * width as f32) as i32;
     let mut v = (uv.y * height as f32) as i32;
@@ -39,11 +42,54 @@ pub fn texture_sample2(texture: &opencv::prelude::Mat, uv: &glm::Vec2) -> glm::V
     if u >= width { u = width - 1; }
     if v < 0 { v = 0; }
     if v >= height { v = height - 1; }
-    let p = *texture.at_2d::<Vec3b>(u, v).unwrap();
+    // 0..255 like the 8 bit texel
+    return texture.texel(u, v).xyz() * 255.;
+}
+
+// texture_sample2 on the mip level picked by texture_lod. there is no mip
+// chain, the texel of the level is the box average of the 2^level x 2^level
+// block of base texels it covers.
+pub fn texture_sample_grad(texture: &Texture, uv: &glm::Vec2, ddx_uv: &glm::Vec2, ddy_uv: &glm::Vec2) -> glm::Vec3{
+    let width = texture.size().1 as i32;
+    let height = texture.size().0 as i32;
+    let level = u32::min(texture_lod(texture, ddx_uv, ddy_uv) as u32, 16);
+    let step = 1i32 << level;
+
+    let u = i32::min(i32::max((uv.x * width as f32) as i32, 0), width - 1);
+    let v = i32::min(i32::max((uv.y * height as f32) as i32, 0), height - 1);
+    let (u0, v0) = (u & !(step - 1), v & !(step - 1));
+    let (u1, v1) = (i32::min(u0 + step, width), i32::min(v0 + step, height));
+    let mut sum = glm::Vec3::zeros();
+    for u in u0..u1 {
+        for v in v0..v1 {
+            sum += texture.texel(u, v).xyz();
+        }
+    }
+    sum * 255. / ((u1 - u0) * (v1 - v0)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texture_sample_grad() {
+        // 4x4 checker board of black and white
+        let texels = (0..16).map(|i| {
+            let c = ((i / 4 + i % 4) % 2) as f32;
+            glm::vec4(c, c, c, 1.)
+        }).collect();
+        let texture = Texture::new(4, 4, texels);
+        let uv = glm::vec2(0.6, 0.1);
+
+        // a quarter texel per pixel stays on the base level
+        let d = glm::vec2(1. / 16., 0.);
+        assert_eq!(texture_lod(&texture, &d, &glm::vec2(0., 0.)), 0.);
+        assert_eq!(texture_sample_grad(&texture, &uv, &d, &d), texture_sample2(&texture, &uv));
 
-    return glm::vec3(
-        p[2] as f32,
-        p[1] as f32,
-        p[0] as f32,
-    );
+        // two texels per pixel average 2x2 blocks into grey
+        let d = glm::vec2(0.5, 0.);
+        assert_eq!(texture_lod(&texture, &d, &glm::vec2(0., 0.)), 1.);
+        assert_eq!(texture_sample_grad(&texture, &uv, &d, &d), glm::vec3(127.5, 127.5, 127.5));
+    }
 }
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+// Shadow maps.
+//
+// Depth of the scene seen from a light, rendered by
+// `Rasterizer::render_shadow_map`. Directional lights use one orthographic
+// view, spot lights one perspective view and point lights a cube of six 90
+// degree views. Fragment shaders get them through the uniform block.
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    DIRECTIONAL,
+    SPOT,
+    POINT,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LightSource {
+    pub kind: LightKind,
+    // for directional lights the center of the shadowed area
+    pub position: glm::Vec3,
+    // unused for point lights
+    pub direction: glm::Vec3,
+    // spot lights: full cone angle in radians
+    // directional lights: half size of the shadowed area
+    pub extent: f32,
+    // directional lights: relative to `position` along the direction
+    pub near: f32,
+    pub far: f32,
+}
+
+impl LightSource {
+    /// Shadows inside a box of `2 * half_size` around `center`.
+    pub fn directional(direction: glm::Vec3, center: glm::Vec3, half_size: f32) -> LightSource {
+        LightSource {
+            kind: LightKind::DIRECTIONAL,
+            position: center,
+            direction: direction.normalize(),
+            extent: half_size,
+            near: -half_size,
+            far: half_size,
+        }
+    }
+
+    pub fn spot(position: glm::Vec3, direction: glm::Vec3, fov: f32) -> LightSource {
+        LightSource {
+            kind: LightKind::SPOT,
+            position,
+            direction: direction.normalize(),
+            extent: fov,
+            near: 0.1,
+            far: 100.,
+        }
+    }
+
+    pub fn point(position: glm::Vec3) -> LightSource {
+        LightSource {
+            kind: LightKind::POINT,
+            position,
+            direction: glm::vec3(0., 0., 1.),
+            extent: std::f32::consts::FRAC_PI_2,
+            near: 0.1,
+            far: 100.,
+        }
+    }
+
+    /// (view, projection) of every face, one for directional and spot
+    /// lights, six for point lights in +x, -x, +y, -y, +z, -z order.
+    pub fn views(&self) -> Vec<(glm::Mat4, glm::Mat4)> {
+        match self.kind {
+            LightKind::DIRECTIONAL => {
+                let eye = self.position - self.direction;
+                let view = glm::look_at_lh(&eye, &self.position, &up_for(&self.direction));
+                let e = self.extent;
+                vec![(view, glm::ortho_lh(-e, e, -e, e, self.near + 1., self.far + 1.))]
+            }
+            LightKind::SPOT => {
+                let view = glm::look_at_lh(&self.position, &(self.position + self.direction), &up_for(&self.direction));
+                vec![(view, glm::perspective_fov_lh(self.extent, 1., 1., self.near, self.far))]
+            }
+            LightKind::POINT => {
+                let projection = glm::perspective_fov_lh(std::f32::consts::FRAC_PI_2, 1., 1., self.near, self.far);
+                CUBE_DIRECTIONS.iter().map(|dir| {
+                    let dir = glm::make_vec3(dir);
+                    (glm::look_at_lh(&self.position, &(self.position + dir), &up_for(&dir)), projection)
+                }).collect()
+            }
+        }
+    }
+}
+
+const CUBE_DIRECTIONS: [[f32; 3]; 6] = [
+    [1., 0., 0.], [-1., 0., 0.],
+    [0., 1., 0.], [0., -1., 0.],
+    [0., 0., 1.], [0., 0., -1.],
+];
+
+// any up vector not parallel to `dir`
+fn up_for(dir: &glm::Vec3) -> glm::Vec3 {
+    if dir.y.abs() > 0.99 { glm::vec3(0., 0., 1.) } else { glm::vec3(0., 1., 0.) }
+}
+
+/// Window depth of one light view, row major with the top row first.
+pub struct ShadowFace {
+    pub view_projection: glm::Mat4,
+    pub size: u32,
+    pub depth: Vec<f32>,
+}
+
+pub struct ShadowMap {
+    pub light: LightSource,
+    pub faces: Vec<ShadowFace>,
+    // subtracted from the fragment depth before the compare
+    pub bias: f32,
+    // world space offset along the normal, against acne on grazing surfaces
+    pub normal_offset: f32,
+    // (2r + 1)^2 texels are compared and averaged
+    pub pcf_radius: i32,
+}
+
+impl ShadowMap {
+    pub fn new(light: LightSource, faces: Vec<ShadowFace>) -> ShadowMap {
+        ShadowMap {
+            light,
+            faces,
+            bias: 0.001,
+            normal_offset: 0.02,
+            pcf_radius: 1,
+        }
+    }
+
+    /// Fraction of light reaching `position`, 0 is fully in shadow.
+    pub fn visibility(&self, position: &glm::Vec3, normal: &glm::Vec3) -> f32 {
+        let p = position + normal * self.normal_offset;
+        let face = match self.light.kind {
+            LightKind::POINT => &self.faces[cube_face(&(p - self.light.position))],
+            _ => &self.faces[0],
+        };
+
+        let clip = face.view_projection * glm::vec4(p.x, p.y, p.z, 1.);
+        if clip.w <= 0. {
+            return 1.;
+        }
+        let ndc = clip.xyz() / clip.w;
+        if ndc.x.abs() > 1. || ndc.y.abs() > 1. || ndc.z > 1. {
+            // outside of the light view
+            return 1.;
+        }
+        let depth = 0.5 * ndc.z + 0.5 - self.bias;
+
+        let size = face.size as i32;
+        let x = ((ndc.x + 1.) * 0.5 * face.size as f32) as i32;
+        let y = ((ndc.y + 1.) * 0.5 * face.size as f32) as i32;
+        let mut lit = 0;
+        let mut count = 0;
+        for dy in -self.pcf_radius..=self.pcf_radius {
+            for dx in -self.pcf_radius..=self.pcf_radius {
+                let sx = i32::min(i32::max(x + dx, 0), size - 1);
+                let sy = i32::min(i32::max(y + dy, 0), size - 1);
+                if depth <= face.depth[((size - 1 - sy) * size + sx) as usize] {
+                    lit += 1;
+                }
+                count += 1;
+            }
+        }
+        lit as f32 / count as f32
+    }
+}
+
+// face of the cube map hit by direction `d`
+fn cube_face(d: &glm::Vec3) -> usize {
+    let a = glm::abs(d);
+    if a.x >= a.y && a.x >= a.z {
+        if d.x >= 0. { 0 } else { 1 }
+    } else if a.y >= a.z {
+        if d.y >= 0. { 2 } else { 3 }
+    } else if d.z >= 0. { 4 } else { 5 }
+}
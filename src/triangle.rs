@@ -1,13 +1,14 @@
 #![allow(dead_code)]
 
+use crate::varying::Varyings;
+
 #[derive(Default)]
 pub struct Triangle {
     pub v: [glm::Vec3; 3],
-    pub color: [glm::Vec3; 3],
-    pub tex_coords: [glm::Vec3; 3],
-    pub normal: [glm::Vec3; 3],
-    pub position: [glm::Vec3; 3],
+    pub varyings: [Varyings; 3],
     pub perp_pos: [glm::Vec4; 3],
+    // instance of the draw it came from
+    pub instance_id: u32,
 }
 
 impl Triangle {
@@ -34,24 +35,9 @@ impl Triangle {
         self.v[ind] = v.clone();
     }
 
-    pub fn set_normal(&mut self, ind: usize, n: &glm::Vec3) {
-        assert!(ind < 3);
-        self.normal[ind] = n.clone();
-    }
-
-    pub fn set_tex_coord(&mut self, ind: usize, s: f32, t: f32) {
-        assert!(ind < 3);
-        self.tex_coords[ind] = glm::vec3(s, t, 1.0);
-    }
-
-    pub fn set_color(&mut self, ind: usize, r: f32, g: f32, b: f32) {
-        assert!(ind < 3);
-        self.color[ind] = glm::vec3(r, g, b);
-    }
-
-    pub fn set_position(&mut self, ind: usize, p: &glm::Vec3) {
+    pub fn set_varyings(&mut self, ind: usize, varyings: &Varyings) {
         assert!(ind < 3);
-        self.position[ind] = p.clone();
+        self.varyings[ind] = varyings.clone();
     }
 
     pub fn set_perp_pos(&mut self, ind:usize, p: &glm::Vec4) {
@@ -0,0 +1,199 @@
+#![allow(dead_code)]
+
+// Constants shared by every vertex and fragment of a draw.
+//
+// Values are looked up by name and typed, textures live in numbered slots.
+// Shaders get the whole block by reference, nothing is copied per fragment.
+
+use std::collections::HashMap;
+
+use opencv::core::{MatTrait, Vec3b};
+use opencv::prelude::Mat;
+
+use crate::shadow::ShadowMap;
+
+pub const MAX_TEXTURE_SLOTS: usize = 8;
+pub const MAX_SHADOW_MAPS: usize = 4;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Uniform {
+    FLOAT(f32),
+    INT(i32),
+    VEC2(glm::Vec2),
+    VEC3(glm::Vec3),
+    VEC4(glm::Vec4),
+    MAT3(glm::Mat3),
+    MAT4(glm::Mat4),
+}
+
+/// Rust types that can be stored in a uniform.
+pub trait UniformValue: Sized {
+    fn into_uniform(self) -> Uniform;
+    fn from_uniform(u: &Uniform) -> Option<Self>;
+}
+
+macro_rules! uniform_value {
+    ($t:ty, $variant:ident) => {
+        impl UniformValue for $t {
+            fn into_uniform(self) -> Uniform {
+                Uniform::$variant(self)
+            }
+
+            fn from_uniform(u: &Uniform) -> Option<Self> {
+                match u {
+                    Uniform::$variant(v) => Some(*v),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+uniform_value!(f32, FLOAT);
+uniform_value!(i32, INT);
+uniform_value!(glm::Vec2, VEC2);
+uniform_value!(glm::Vec3, VEC3);
+uniform_value!(glm::Vec4, VEC4);
+uniform_value!(glm::Mat3, MAT3);
+uniform_value!(glm::Mat4, MAT4);
+
+/// Texture of the uniform block. Bound Mats are copied into one, so shaders
+/// only ever read plain memory the tile threads can share.
+pub struct Texture {
+    // columns and rows
+    width: usize,
+    height: usize,
+    // rgba in [0, 1], row major, top row first like the Mat
+    texels: Vec<glm::Vec4>,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, texels: Vec<glm::Vec4>) -> Texture {
+        assert_eq!(texels.len(), width * height, "texture of {}x{} needs {} texels", width, height, width * height);
+        Texture { width, height, texels }
+    }
+
+    /// Copy of an 8 bit bgr Mat, fails for other element types.
+    pub fn from_mat(mat: &Mat) -> opencv::Result<Texture> {
+        let mut texels = Vec::with_capacity((mat.rows() * mat.cols()) as usize);
+        for row in 0..mat.rows() {
+            for col in 0..mat.cols() {
+                let p = mat.at_2d::<Vec3b>(row, col)?;
+                texels.push(glm::vec4(p[2] as f32 / 255., p[1] as f32 / 255., p[0] as f32 / 255., 1.));
+            }
+        }
+        Ok(Texture::new(mat.cols() as usize, mat.rows() as usize, texels))
+    }
+
+    /// (columns, rows)
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// rgba texel at `row`, `col`, clamped to the edge.
+    pub fn texel(&self, row: i32, col: i32) -> glm::Vec4 {
+        let row = i32::min(i32::max(row, 0), self.height as i32 - 1) as usize;
+        let col = i32::min(i32::max(col, 0), self.width as i32 - 1) as usize;
+        self.texels[row * self.width + col]
+    }
+}
+
+pub struct Uniforms {
+    values: HashMap<String, Uniform>,
+    textures: Vec<Option<Texture>>,
+    shadow_maps: Vec<Option<ShadowMap>>,
+}
+
+impl Default for Uniforms {
+    fn default() -> Self {
+        Uniforms {
+            values: HashMap::new(),
+            textures: (0..MAX_TEXTURE_SLOTS).map(|_| None).collect(),
+            shadow_maps: (0..MAX_SHADOW_MAPS).map(|_| None).collect(),
+        }
+    }
+}
+
+impl Uniforms {
+    pub fn new() -> Uniforms {
+        Default::default()
+    }
+
+    pub fn set<T: UniformValue>(&mut self, name: &str, value: T) {
+        self.values.insert(name.to_string(), value.into_uniform());
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    /// `None` when the uniform is missing or has another type.
+    pub fn get<T: UniformValue>(&self, name: &str) -> Option<T> {
+        self.values.get(name).and_then(T::from_uniform)
+    }
+
+    pub fn get_raw(&self, name: &str) -> Option<&Uniform> {
+        self.values.get(name)
+    }
+
+    pub fn bind_texture(&mut self, slot: usize, texture: Texture) {
+        assert!(slot < MAX_TEXTURE_SLOTS, "texture slot {} out of range", slot);
+        self.textures[slot] = Some(texture);
+    }
+
+    pub fn unbind_texture(&mut self, slot: usize) {
+        assert!(slot < MAX_TEXTURE_SLOTS, "texture slot {} out of range", slot);
+        self.textures[slot] = None;
+    }
+
+    pub fn texture(&self, slot: usize) -> Option<&Texture> {
+        self.textures.get(slot).and_then(|t| t.as_ref())
+    }
+
+    pub fn bind_shadow_map(&mut self, slot: usize, shadow_map: ShadowMap) {
+        assert!(slot < MAX_SHADOW_MAPS, "shadow map slot {} out of range", slot);
+        self.shadow_maps[slot] = Some(shadow_map);
+    }
+
+    pub fn unbind_shadow_map(&mut self, slot: usize) {
+        assert!(slot < MAX_SHADOW_MAPS, "shadow map slot {} out of range", slot);
+        self.shadow_maps[slot] = None;
+    }
+
+    pub fn shadow_map(&self, slot: usize) -> Option<&ShadowMap> {
+        self.shadow_maps.get(slot).and_then(|m| m.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_uniforms() {
+        let mut uniforms = Uniforms::new();
+        uniforms.set("eye_pos", glm::vec3(0f32, 1., 2.));
+        uniforms.set("shininess", 150f32);
+
+        assert_eq!(uniforms.get::<glm::Vec3>("eye_pos"), Some(glm::vec3(0., 1., 2.)));
+        assert_eq!(uniforms.get::<f32>("shininess"), Some(150.));
+        // wrong type or name
+        assert_eq!(uniforms.get::<i32>("shininess"), None);
+        assert_eq!(uniforms.get::<f32>("missing"), None);
+
+        uniforms.set("shininess", 32i32);
+        assert_eq!(uniforms.get::<i32>("shininess"), Some(32));
+        assert!(uniforms.texture(0).is_none());
+    }
+
+    #[test]
+    fn test_texture_clamps() {
+        // 2 columns, 1 row
+        let texture = Texture::new(2, 1, vec![glm::vec4(1., 0., 0., 1.), glm::vec4(0., 1., 0., 1.)]);
+        assert_eq!(texture.size(), (2, 1));
+        assert_eq!(texture.texel(0, 1), glm::vec4(0., 1., 0., 1.));
+        assert_eq!(texture.texel(-3, -1), glm::vec4(1., 0., 0., 1.));
+        assert_eq!(texture.texel(5, 7), glm::vec4(0., 1., 0., 1.));
+    }
+}
@@ -12,6 +12,11 @@ pub fn draw_line(
     end: &glm::Vec3,
     mut f_action: Box<dyn FnMut(&glm::Vec3, &glm::Vec3) + '_>,
 ) {
+    // stepping from an infinite end point never terminates
+    if !begin.iter().chain(end.iter()).all(|v| v.is_finite()) {
+        return;
+    }
+
     let x1: f32 = begin.x;
     let y1: f32 = begin.y;
     let x2: f32 = end.x;
@@ -0,0 +1,175 @@
+#![allow(dead_code)]
+
+// Per vertex outputs of the vertex shader that get interpolated across the
+// primitive and handed to the frame shader.
+//
+// A varying block is a flat list of floats, every float tagged with how it is
+// interpolated. Shaders agree on the layout by offset, the rasterizer only
+// sees floats.
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// value of the provoking (first) vertex, no interpolation
+    FLAT,
+    /// perspective correct
+    SMOOTH,
+    /// linear in screen space
+    NO_PERSPECTIVE,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::SMOOTH
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Varyings {
+    values: Vec<f32>,
+    interpolation: Vec<Interpolation>,
+}
+
+impl Varyings {
+    pub fn new() -> Varyings {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Append `values`, returns the offset of the first one.
+    pub fn push(&mut self, interpolation: Interpolation, values: &[f32]) -> usize {
+        let offset = self.values.len();
+        self.values.extend_from_slice(values);
+        self.interpolation.resize(self.values.len(), interpolation);
+        offset
+    }
+
+    pub fn push_vec2(&mut self, interpolation: Interpolation, v: &glm::Vec2) -> usize {
+        self.push(interpolation, v.as_slice())
+    }
+
+    pub fn push_vec3(&mut self, interpolation: Interpolation, v: &glm::Vec3) -> usize {
+        self.push(interpolation, v.as_slice())
+    }
+
+    pub fn push_vec4(&mut self, interpolation: Interpolation, v: &glm::Vec4) -> usize {
+        self.push(interpolation, v.as_slice())
+    }
+
+    /// Missing components read as 0.
+    pub fn get(&self, offset: usize) -> f32 {
+        self.values.get(offset).copied().unwrap_or(0.)
+    }
+
+    pub fn set(&mut self, offset: usize, value: f32) {
+        self.values[offset] = value;
+    }
+
+    pub fn interpolation(&self, offset: usize) -> Interpolation {
+        self.interpolation[offset]
+    }
+
+    pub fn vec2(&self, offset: usize) -> glm::Vec2 {
+        glm::vec2(self.get(offset), self.get(offset + 1))
+    }
+
+    pub fn vec3(&self, offset: usize) -> glm::Vec3 {
+        glm::vec3(self.get(offset), self.get(offset + 1), self.get(offset + 2))
+    }
+
+    pub fn vec4(&self, offset: usize) -> glm::Vec4 {
+        glm::vec4(self.get(offset), self.get(offset + 1), self.get(offset + 2), self.get(offset + 3))
+    }
+
+    /// Point between `a` and `b` made by the clipper, `t` is the clip space
+    /// parameter. `w_a` and `w_b` are the clip space w of the end points.
+    pub fn lerp(a: &Varyings, b: &Varyings, t: f32, w_a: f32, w_b: f32) -> Varyings {
+        debug_assert_eq!(a.len(), b.len());
+        // the same point as a screen space parameter
+        let w = glm::lerp_scalar(w_a, w_b, t);
+        let t_screen = if w != 0. { t * w_b / w } else { t };
+
+        let mut out = a.clone();
+        for i in 0..out.len() {
+            out.values[i] = match a.interpolation[i] {
+                Interpolation::FLAT => a.values[i],
+                Interpolation::SMOOTH => glm::lerp_scalar(a.values[i], b.values[i], t),
+                Interpolation::NO_PERSPECTIVE => glm::lerp_scalar(a.values[i], b.values[i], t_screen),
+            };
+        }
+        out
+    }
+
+    /// Component wise `self - other`, for derivatives.
+    pub fn difference(&self, other: &Varyings) -> Varyings {
+        let mut out = self.clone();
+        for (v, o) in out.values.iter_mut().zip(other.values.iter()) {
+            *v -= o;
+        }
+        out
+    }
+
+    /// Take every flat value from the provoking vertex.
+    pub fn copy_flat(&mut self, provoking: &Varyings) {
+        for i in 0..self.len() {
+            if self.interpolation[i] == Interpolation::FLAT {
+                self.values[i] = provoking.values[i];
+            }
+        }
+    }
+
+    /// Value inside a triangle. `barycentric` is in screen space, `w` holds
+    /// the clip space w of the vertices.
+    pub fn interpolate(v: &[Varyings; 3], barycentric: &glm::Vec3, w: &[f32; 3]) -> Varyings {
+        debug_assert!(v[0].len() == v[1].len() && v[0].len() == v[2].len());
+        let persp = glm::vec3(barycentric[0] / w[0], barycentric[1] / w[1], barycentric[2] / w[2]);
+        let persp = persp / (persp[0] + persp[1] + persp[2]);
+
+        let mut out = v[0].clone();
+        for i in 0..out.len() {
+            let weights = match v[0].interpolation[i] {
+                Interpolation::FLAT => continue,
+                Interpolation::SMOOTH => &persp,
+                Interpolation::NO_PERSPECTIVE => barycentric,
+            };
+            out.values[i] = weights[0] * v[0].values[i] + weights[1] * v[1].values[i] + weights[2] * v[2].values[i];
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let vertex = |value: f32| {
+            let mut v = Varyings::new();
+            v.push(Interpolation::FLAT, &[value]);
+            v.push(Interpolation::SMOOTH, &[value]);
+            v.push(Interpolation::NO_PERSPECTIVE, &[value]);
+            v
+        };
+        let v = [vertex(0.), vertex(1.), vertex(2.)];
+        // the second vertex is three times farther away
+        let w = [1f32, 3., 1.];
+        let out = Varyings::interpolate(&v, &glm::vec3(0.5, 0.5, 0.), &w);
+        assert_eq!(out.get(0), 0.);
+        assert!((out.get(1) - 0.25).abs() < 1e-6);
+        assert!((out.get(2) - 0.5).abs() < 1e-6);
+
+        // the clipper must produce the same values
+        let mid = Varyings::lerp(&v[0], &v[1], 0.25, w[0], w[1]);
+        assert_eq!(mid.get(0), 0.);
+        assert!((mid.get(1) - 0.25).abs() < 1e-6);
+        assert!((mid.get(2) - 0.5).abs() < 1e-6);
+    }
+}